@@ -0,0 +1,115 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOptions};
+use std::time::Duration;
+use tempfile::TempDir;
+
+const RECORDS: usize = 1000;
+const RECOVERY_RECORDS: [usize; 3] = [1_000, 10_000, 50_000];
+// Keys the overwritten histories cycle through
+const HOT_KEYS: usize = 100;
+const SMALL_RECORDS: usize = 10_000;
+
+fn records() -> Vec<(String, String)> {
+    (0..RECORDS)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect()
+}
+
+fn set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+
+    group.bench_function("set_loop", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                (temp_dir, store, records())
+            },
+            |(_temp_dir, mut store, records)| {
+                for (key, value) in records {
+                    store.set(key, value).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("set_all", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                (temp_dir, store, records())
+            },
+            |(_temp_dir, mut store, records)| {
+                store.set_all(records).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+// Many 10-byte sets, each written out on its own or coalesced with a flush
+// interval
+fn small_set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("small_set");
+
+    for (name, flush_interval) in [
+        ("flush_each", None),
+        ("coalesced", Some(Duration::from_millis(10))),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let mut options = KvStoreOptions::new();
+                    if let Some(interval) = flush_interval {
+                        options = options.flush_interval(interval);
+                    }
+                    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                    (temp_dir, store)
+                },
+                |(_temp_dir, mut store)| {
+                    for i in 0..SMALL_RECORDS {
+                        store.set(format!("k{:04}", i), "v".repeat(5)).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+// Time to open a store as a function of how many records were written to
+// it. Unique keys all stay live, overwritten ones leave a history of dead
+// records. Written with compaction off, so the log grows with the records
+// either way
+fn recovery_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(20);
+
+    for records in RECOVERY_RECORDS {
+        for (name, key_space) in [("unique", records), ("overwritten", HOT_KEYS)] {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new().disable_compaction();
+            let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+            store
+                .set_all((0..records).map(|i| (format!("key{}", i % key_space), "v".repeat(100))))
+                .unwrap();
+            drop(store);
+
+            group.bench_with_input(BenchmarkId::new(name, records), &temp_dir, |b, temp_dir| {
+                b.iter(|| KvStore::open(temp_dir.path()).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, small_set_bench, recovery_bench);
+criterion_main!(benches);
@@ -1,471 +1,3639 @@
+// `failure_derive` emits its impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
 use chrono::prelude::*;
-use failure::{Error, Fail};
+use failure::{Error, Fail, ResultExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
-use std::fs::File;
 use std::fs::OpenOptions;
+use std::fs::{File, TryLockError};
+use std::io;
 use std::io::BufWriter;
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::mem;
+use std::ops::{Bound, Range};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tracing::{debug, info, info_span, warn};
 
 const COMPACTION_THRESHOLD: usize = 1024 * 1024;
-const LOG_FILE_PREFIX: &str = "kvlog";
-const LOG_FILE_EXTENSION: &str = "cmdlog";
-
+const MIN_DEAD_RATIO_COMPACTION_BYTES: u64 = 64 * 1024;
+const DEFAULT_LOG_FILE_PREFIX: &str = "kvlog";
+const DEFAULT_LOG_FILE_EXTENSION: &str = "cmdlog";
+const ENGINE_MARKER_FILE_NAME: &str = "engine";
+const ENGINE_NAME: &str = "kvs";
+const FORMAT_VERSION: u32 = 3;
+const COMPACTION_STAGING_DIR_NAME: &str = ".compacting";
+const COMPACTION_COMMITTED_DIR_NAME: &str = ".compacted";
+const COMPACTION_MANIFEST_FILE_NAME: &str = "manifest";
+const LOCK_FILE_NAME: &str = "lock";
+// How often `lock_timeout` retries a held lock
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Records replayed between recovery progress events
+const RECOVERY_PROGRESS_RECORDS: u64 = 100_000;
+// Records start with a tag byte telling how they're framed. Format 1 logs
+// hold newline terminated JSON, which always starts with `{`
+const LEGACY_RECORD_TAG: u8 = b'{';
+// Tag, little endian u32 payload length, payload
+const FRAMED_RECORD_TAG: u8 = 1;
+const FRAMED_RECORD_HEADER_LEN: usize = 5;
+// Larger log files of read-only stores are read through the file instead of
+// being memory-mapped
+#[cfg(feature = "mmap")]
+const MMAP_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
 struct LogPosition {
     pos: u64,
+    // Size of the record in the log, header included
+    size: u64,
+    // Length of the value set by the record, 0 for tombstones
+    value_len: usize,
     log_file_name: String,
 }
 
 pub type CommandResult<T> = Result<T, Error>;
 
-#[derive(Serialize, Deserialize)]
-enum CommandLog {
-    Set { key: String, value: String },
-    Remove { key: String },
+// A write, as replayed by `changes_since`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommandLog {
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+        // Milliseconds since the epoch, missing on tombstones written before
+        // retention existed
+        removed_at: Option<i64>,
+    },
 }
 
-#[derive(Fail, Debug)]
-pub enum KvSError {
-    #[fail(display = "Key not provided for command")]
-    KeyNotProvided,
-    #[fail(display = "Key not found")]
-    KeyNotFound,
+// A live key and its value, as returned by scans and written by `export`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
 }
 
-pub struct KvStore {
-    key_dir: KeyDir,
-    writer_pool: WriterPool,
-    reader_pool: ReaderPool,
+// Turns log records into bytes and back. The codec's name is recorded in
+// the engine marker, and a store only opens with the codec it was written
+// with
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+    fn encode(&self, seq: u64, command: &CommandLog) -> CommandResult<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> CommandResult<(u64, CommandLog)>;
 }
 
-impl KvStore {
-    pub fn open(path: impl Into<PathBuf>) -> CommandResult<KvStore> {
-        let path = path.into();
-
-        // Create directory if it doesn't exist
-        fs::create_dir_all(&path)?;
+const JSON_CODEC_NAME: &str = "json";
 
-        // Initialize map with command logs from previous sessions
-        let key_dir = KeyDir::init_with_command_logs(&path);
-        let writer_pool = WriterPool::new(&path);
-        let reader_pool = ReaderPool::new(&path);
+// The default. Also the only codec that reads logs from before codecs
+// were selectable
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
 
-        Ok(KvStore {
-            key_dir,
-            writer_pool,
-            reader_pool,
-        })
+impl Codec for JsonCodec {
+    fn name(&self) -> &str {
+        JSON_CODEC_NAME
     }
 
-    pub fn get(&mut self, key: String) -> CommandResult<Option<String>> {
-        self.writer_pool.sync()?;
+    fn encode(&self, seq: u64, command: &CommandLog) -> CommandResult<Vec<u8>> {
+        Ok(serde_json::to_vec(&Record::new(seq, command))?)
+    }
 
-        let res = self.key_dir.get(&key).clone();
-        match res {
-            Some(log_pos) => {
-                let line_res = self.reader_pool.read_from_pos_to_eol(log_pos)?;
-                let command_log: CommandLog = serde_json::from_str(&line_res)?;
-                match command_log {
-                    CommandLog::Set { value, .. } => Ok(Some(value)),
-                    _ => Ok(None),
-                }
-            }
-            _ => Ok(None),
-        }
+    fn decode(&self, bytes: &[u8]) -> CommandResult<(u64, CommandLog)> {
+        Ok(serde_json::from_slice::<Record<String>>(bytes)?.into_parts())
     }
+}
 
-    pub fn set(&mut self, key: String, value: String) -> CommandResult<()> {
-        if key.is_empty() {
-            return Err(KvSError::KeyNotProvided.into());
-        }
+// Smaller records and faster decoding than JSON, but not human readable
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
 
-        let pos = self.write_command_log(CommandLog::Set {
-            key: key.clone(),
-            value: value.clone(),
-        })?;
+impl Codec for BincodeCodec {
+    fn name(&self) -> &str {
+        "bincode"
+    }
 
-        self.key_dir.set(key, pos);
+    fn encode(&self, seq: u64, command: &CommandLog) -> CommandResult<Vec<u8>> {
+        Ok(bincode::serialize(&(seq, command))?)
+    }
 
-        Ok(())
+    fn decode(&self, bytes: &[u8]) -> CommandResult<(u64, CommandLog)> {
+        Ok(bincode::deserialize(bytes)?)
     }
+}
 
-    pub fn remove(&mut self, key: String) -> CommandResult<()> {
-        if key.is_empty() {
-            return Err(KvSError::KeyNotProvided.into());
-        }
+// How `JsonCodec` lays out a record: a `CommandLog` plus its sequence number,
+// which orders writes across log files and is 0 for records written before
+// writes were sequenced. Kept as a mirror of `CommandLog` rather than a
+// flattened wrapper, which serde can only deserialize through a buffer
+#[derive(Serialize, Deserialize)]
+enum Record<S> {
+    Set {
+        key: S,
+        value: S,
+        #[serde(default, skip_serializing_if = "is_unsequenced")]
+        seq: u64,
+    },
+    Remove {
+        key: S,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        removed_at: Option<i64>,
+        #[serde(default, skip_serializing_if = "is_unsequenced")]
+        seq: u64,
+    },
+}
 
-        if !self.key_dir.contains_key(&key) {
-            return Err(KvSError::KeyNotFound.into());
+impl<'a> Record<&'a str> {
+    fn new(seq: u64, command: &'a CommandLog) -> Record<&'a str> {
+        match command {
+            CommandLog::Set { key, value } => Record::Set { key, value, seq },
+            CommandLog::Remove { key, removed_at } => Record::Remove {
+                key,
+                removed_at: *removed_at,
+                seq,
+            },
         }
-
-        self.write_command_log(CommandLog::Remove { key: key.clone() })?;
-
-        self.key_dir.remove(&key);
-
-        Ok(())
     }
+}
 
-    fn write_command_log(&mut self, command_log: CommandLog) -> Result<LogPosition, Error> {
-        let serialized_log = serde_json::to_string(&command_log)?;
-        if self.writer_pool.active_size() + serialized_log.len() >= COMPACTION_THRESHOLD {
-            self.compact_log_files()?;
+impl Record<String> {
+    fn into_parts(self) -> (u64, CommandLog) {
+        match self {
+            Record::Set { key, value, seq } => (seq, CommandLog::Set { key, value }),
+            Record::Remove {
+                key,
+                removed_at,
+                seq,
+            } => (seq, CommandLog::Remove { key, removed_at }),
         }
-
-        self.writer_pool.write(serialized_log)
     }
+}
 
-    fn compact_log_files(&mut self) -> Result<(), Error> {
-        let reader_list = self.reader_pool.reader_list();
+fn is_unsequenced(seq: &u64) -> bool {
+    *seq == 0
+}
 
-        self.writer_pool.new_writer();
-        self.reader_pool.add_reader(self.writer_pool.curr.clone());
+#[derive(Fail, Debug)]
+pub enum KvSError {
+    #[fail(display = "Key not provided for command")]
+    KeyNotProvided,
+    // Display stays the bare message the CLI prints for `kvs rm`
+    #[fail(display = "Key not found")]
+    KeyNotFound { key: String },
+    #[fail(display = "Key is {} bytes, longer than the limit of {}", size, limit)]
+    KeyTooLarge { size: usize, limit: usize },
+    #[fail(display = "Record is {} bytes, too long for its length header", size)]
+    RecordTooLarge { size: usize },
+    #[fail(
+        display = "Unsupported log format version {}, supported up to {}",
+        found, supported
+    )]
+    UnsupportedFormat { found: u32, supported: u32 },
+    #[fail(
+        display = "Corrupt log {}: record at position {} runs past the end of the file",
+        file_name, pos
+    )]
+    CorruptLog { file_name: String, pos: u64 },
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    #[fail(display = "Store is already opened for writing")]
+    Locked,
+    #[fail(
+        display = "Directory was written by engine {}, expected {}",
+        found, expected
+    )]
+    WrongEngine { found: String, expected: String },
+    #[fail(
+        display = "Log was written with the {} codec, opened with {}",
+        found, expected
+    )]
+    CodecMismatch { found: String, expected: String },
+    #[fail(
+        display = "Log was written with the {} layout, opened with {}",
+        found, expected
+    )]
+    LayoutMismatch { found: String, expected: String },
+    #[fail(display = "Value reference was invalidated by a compaction")]
+    StaleValueRef,
+}
 
-        let mut start_pos = 0;
+// Written next to the log files so binaries can tell which engine and
+// log format version produced a directory
+#[derive(Serialize, Deserialize)]
+struct EngineMarker {
+    engine: String,
+    format_version: u32,
+    // Missing from markers written before codecs were selectable
+    #[serde(default = "json_codec_name")]
+    codec: String,
+    // Highest sequence number handed out when the last rewrite committed.
+    // The records carrying it may have been dropped, so recovery carries on
+    // from here when the log holds nothing higher
+    #[serde(default)]
+    last_seq: u64,
+    // Missing from markers written before the layout was recorded
+    #[serde(default)]
+    layout: Option<LogLayout>,
+}
 
-        reader_list.iter().for_each(|file_name| {
-            let reader = self.reader_pool.get_reader(file_name.to_string());
-            let lines: Vec<String> = reader.lines().map(|line| line.unwrap()).collect();
+fn json_codec_name() -> String {
+    JSON_CODEC_NAME.to_string()
+}
 
-            for line in lines {
-                let command_log: CommandLog = serde_json::from_str(&line).unwrap();
-                let should_remove =
-                    self.should_remove_log(&command_log, file_name.clone(), start_pos);
+// Absent keys are not an error when reading: `get` returns `Ok(None)`.
+// Removing one fails with `KvSError::KeyNotFound` naming the key
+pub trait KvsEngine {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()>;
+    fn get(&mut self, key: String) -> CommandResult<Option<String>>;
+    fn remove(&mut self, key: String) -> CommandResult<()>;
+}
 
-                start_pos += line.len() as u64 + 1;
+// Where log files are placed inside the store directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogLayout {
+    // Every log file directly in the store directory
+    #[default]
+    Flat,
+    // Log files grouped into subdirectories, each holding a range of
+    // `bucket_size` generations
+    Nested {
+        bucket_size: u64,
+    },
+}
 
-                if should_remove {
-                    continue;
-                }
+// How log files are named and placed in the store directory. Files are
+// named `<prefix>_<generation>.<extension>`
+#[derive(Clone, Debug)]
+struct LogFiles {
+    layout: LogLayout,
+    prefix: String,
+    extension: String,
+}
 
-                let serialized_log = serde_json::to_string(&command_log).unwrap();
+impl LogFiles {
+    fn file_name(&self, generation: u64) -> String {
+        format!("{}_{}.{}", self.prefix, generation, self.extension)
+    }
 
-                if self.writer_pool.active_size() + serialized_log.len() >= COMPACTION_THRESHOLD {
-                    self.writer_pool.new_writer();
-                    self.reader_pool.add_reader(self.writer_pool.curr.clone());
-                }
+    fn log_file_path(&self, path: &Path, file_name: &str) -> PathBuf {
+        match self.layout {
+            LogLayout::Flat => path.join(file_name),
+            LogLayout::Nested { bucket_size } => {
+                let generation = self.generation(file_name).unwrap_or(0);
+                path.join((generation / bucket_size.max(1)).to_string())
+                    .join(file_name)
+            }
+        }
+    }
 
-                self.writer_pool.write(serialized_log).unwrap();
+    // The one place that decides what an entry of the store directory is.
+    // Only `<prefix>_<generation>.<extension>` files are logs; anything the
+    // store doesn't recognise, e.g. a README or a copied-in backup, is
+    // foreign and never read, moved or deleted
+    fn classify(&self, file_name: &str, is_dir: bool) -> StoreEntry {
+        match file_name {
+            LOCK_FILE_NAME if !is_dir => StoreEntry::Lock,
+            ENGINE_MARKER_FILE_NAME if !is_dir => StoreEntry::EngineMarker,
+            COMPACTION_STAGING_DIR_NAME | COMPACTION_COMMITTED_DIR_NAME if is_dir => {
+                StoreEntry::Compaction
             }
-        });
+            _ if is_dir => match self.layout {
+                LogLayout::Nested { .. } if file_name.parse::<u64>().is_ok() => StoreEntry::Bucket,
+                _ => StoreEntry::Foreign,
+            },
+            _ => match self.generation(file_name) {
+                Some(generation) => StoreEntry::Log { generation },
+                None => StoreEntry::Foreign,
+            },
+        }
+    }
 
-        self.reader_pool.remove_readers(reader_list);
+    // Parses the generation out of a log file name
+    fn generation(&self, file_name: &str) -> Option<u64> {
+        file_name
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('_')?
+            .strip_suffix(self.extension.as_str())?
+            .strip_suffix('.')?
+            .parse()
+            .ok()
+    }
 
-        Ok(())
+    fn order(&self, file_name: &str) -> (u64, String) {
+        (
+            self.generation(file_name).unwrap_or(0),
+            file_name.to_string(),
+        )
     }
+}
 
-    fn should_remove_log(&self, log: &CommandLog, file_name: String, start_pos: u64) -> bool {
-        match log {
-            CommandLog::Set { key, .. } => {
-                if !self.key_dir.contains_key(&key) {
-                    return true;
-                }
+// What an entry of the store directory is, see `LogFiles::classify`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StoreEntry {
+    Log { generation: u64 },
+    // Directory holding a range of generations with `LogLayout::Nested`
+    Bucket,
+    Lock,
+    EngineMarker,
+    // Staging or committed directory of an unfinished compaction
+    Compaction,
+    Foreign,
+}
 
-                let log_pos = self.key_dir.get(&key).unwrap();
-                if log_pos.log_file_name != file_name {
-                    return true;
-                }
-                if log_pos.pos != start_pos {
-                    return true;
-                }
+// Source of the current time, so tests can control it instead of sleeping
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
 
-                return false;
-            }
-            CommandLog::Remove { key: _ } => true,
-        }
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
     }
 }
 
-struct KeyDir {
-    map: HashMap<String, LogPosition>,
+// Clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
 }
 
-impl KeyDir {
-    fn init_with_command_logs(path: impl Into<PathBuf>) -> KeyDir {
-        let mut store = HashMap::new();
-        let log_files = list_log_files(path).unwrap();
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
 
-        for file_path in log_files {
-            let file = File::open(file_path.clone()).unwrap();
-            let reader = BufReader::new(file);
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
 
-            let mut pos = 0;
-            for line in reader.lines() {
-                let line = line.unwrap();
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
 
-                let command_log: CommandLog = serde_json::from_str(&line).unwrap();
-                match command_log {
-                    CommandLog::Set { key, .. } => {
-                        store.insert(
-                            key,
-                            LogPosition {
-                                pos,
-                                log_file_name: file_path
-                                    .file_name()
-                                    .unwrap()
-                                    .to_str()
-                                    .unwrap()
-                                    .to_string(),
-                            },
-                        );
-                    }
-                    CommandLog::Remove { key } => {
-                        store.remove(&key);
-                    }
-                }
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
 
-                pos += line.len() as u64 + 1;
-            }
-        }
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreOptions {
+    layout: LogLayout,
+    max_open_readers: Option<usize>,
+    recovery_threads: Option<usize>,
+    value_cache_capacity: usize,
+    on_evict: Option<EvictCallback>,
+    clock: Option<Arc<dyn Clock>>,
+    tombstone_retention: Option<chrono::Duration>,
+    codec: Option<Arc<dyn Codec>>,
+    sync_interval: Option<Duration>,
+    initial_generation: Option<u64>,
+    max_key_bytes: Option<usize>,
+    log_file_prefix: Option<String>,
+    log_file_extension: Option<String>,
+    idempotent_remove: bool,
+    compaction_dead_ratio: Option<f64>,
+    flush_interval: Option<Duration>,
+    max_unflushed_bytes: Option<usize>,
+    lock_timeout: Option<Duration>,
+    max_generations: Option<usize>,
+    background_compaction: bool,
+    compaction_disabled: bool,
+    mirror: Option<PathBuf>,
+    mirror_errors_fail_writes: bool,
+}
 
-        KeyDir { map: store }
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
     }
 
-    fn get(&self, key: &str) -> Option<&LogPosition> {
-        self.map.get(key)
+    // Recorded in the directory when it's first opened. Opening it again
+    // with another layout fails with `KvSError::LayoutMismatch`
+    pub fn layout(mut self, layout: LogLayout) -> KvStoreOptions {
+        self.layout = layout;
+        self
     }
 
-    fn set(&mut self, key: String, log_position: LogPosition) {
-        self.map.insert(key, log_position);
+    // Log files are named `<prefix>_<generation>.<extension>`,
+    // `kvlog_<generation>.cmdlog` by default. Only files with the configured
+    // prefix and extension are read, but a directory still holds one store:
+    // the lock, the engine marker and compaction's staging directories are
+    // shared by every prefix
+    pub fn log_file_prefix(mut self, prefix: impl Into<String>) -> KvStoreOptions {
+        self.log_file_prefix = Some(prefix.into());
+        self
     }
 
-    fn remove(&mut self, key: &str) {
-        self.map.remove(key);
+    pub fn log_file_extension(mut self, extension: impl Into<String>) -> KvStoreOptions {
+        self.log_file_extension = Some(extension.into());
+        self
     }
 
-    fn contains_key(&self, key: &str) -> bool {
-        self.map.contains_key(key)
+    // Caps how many log files are kept open for reading at once, unbounded
+    // by default
+    pub fn max_open_readers(mut self, max_open_readers: usize) -> KvStoreOptions {
+        self.max_open_readers = Some(max_open_readers);
+        self
     }
-}
 
-struct WriterPool {
-    path: PathBuf,
-    writers: HashMap<String, NamedBufWriter>,
-    curr: String,
-    curr_size: usize,
-}
+    // How many log files recovery reads at once, each on a thread of its
+    // own, 1 by default. The files are still applied in generation order,
+    // so the result is the same as reading them one after the other
+    pub fn recovery_threads(mut self, threads: usize) -> KvStoreOptions {
+        self.recovery_threads = Some(threads);
+        self
+    }
 
-impl WriterPool {
-    // Create hash map with writers to log files, initialized with empty log file
-    fn new(path: impl Into<PathBuf>) -> WriterPool {
-        let mut writers = HashMap::new();
-        let path = path.into();
+    // Keeps up to `capacity` recently read values in memory, disabled by
+    // default
+    pub fn value_cache_capacity(mut self, capacity: usize) -> KvStoreOptions {
+        self.value_cache_capacity = capacity;
+        self
+    }
 
-        match latest_log_file_metadata(&path) {
-            Ok((lf_name, lf_size)) => {
-                if lf_size < COMPACTION_THRESHOLD as u64 {
-                    writers.insert(lf_name.clone(), NamedBufWriter::new(&path, lf_name.clone()));
-                    return WriterPool {
-                        path,
-                        writers,
-                        curr: lf_name,
-                        curr_size: lf_size as usize,
-                    };
-                }
-            }
-            _ => {}
-        }
+    // Called with the key of every value the value cache evicts to make
+    // room for another. Values dropped because their key was written, or by
+    // `reload`, don't count. Runs on the thread of the read that caused it
+    pub fn on_evict(mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> KvStoreOptions {
+        self.on_evict = Some(EvictCallback(Arc::new(callback)));
+        self
+    }
 
-        let new_log_file_name = new_log_file_name();
-        writers.insert(
-            new_log_file_name.clone(),
-            NamedBufWriter::new(&path, new_log_file_name.clone()),
-        );
+    // Rejects writes of keys longer than `max_key_bytes` with
+    // `KvSError::KeyTooLarge`, since every live key is held in memory.
+    // Unlimited by default
+    pub fn max_key_bytes(mut self, max_key_bytes: usize) -> KvStoreOptions {
+        self.max_key_bytes = Some(max_key_bytes);
+        self
+    }
 
-        WriterPool {
-            path,
-            writers,
-            curr: new_log_file_name,
-            curr_size: 0,
-        }
+    // Makes removing an absent key succeed without writing anything,
+    // instead of failing with `KvSError::KeyNotFound`
+    pub fn idempotent_remove(mut self, idempotent_remove: bool) -> KvStoreOptions {
+        self.idempotent_remove = idempotent_remove;
+        self
     }
 
-    fn new_writer(&mut self) {
-        let new_log_file_name = new_log_file_name();
-        self.writers.insert(
-            new_log_file_name.clone(),
-            NamedBufWriter::new(&self.path, new_log_file_name.clone()),
-        );
-        self.curr = new_log_file_name;
-        self.curr_size = 0;
+    // Generation of the first log file in an empty directory, 1 by default.
+    // Otherwise the next generation follows the highest existing one
+    pub fn initial_generation(mut self, generation: u64) -> KvStoreOptions {
+        self.initial_generation = Some(generation);
+        self
     }
 
-    fn active_size(&self) -> usize {
-        self.curr_size
+    // Clock used for compaction timestamps and tombstone retention, the
+    // system clock by default
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> KvStoreOptions {
+        self.clock = Some(clock);
+        self
     }
 
-    fn sync(&mut self) -> Result<(), Error> {
-        self.writers.get_mut(&self.curr).unwrap().sync()?;
-        Ok(())
+    // Keeps tombstones through compactions until they are older than
+    // `retention`, so recent removes stay visible in the log. Dropped at
+    // the first compaction by default
+    pub fn tombstone_retention(mut self, retention: chrono::Duration) -> KvStoreOptions {
+        self.tombstone_retention = Some(retention);
+        self
     }
 
-    fn write(&mut self, s: String) -> Result<LogPosition, Error> {
-        self.curr_size += s.len();
-        return self.writers.get_mut(&self.curr).unwrap().write(s);
+    // Also compacts when at least `ratio` of the log is dead, i.e.
+    // overwritten values and tombstones, rather than only when the active
+    // file is full. Off by default
+    pub fn compaction_dead_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_dead_ratio = Some(ratio);
+        self
     }
-}
 
-struct ReaderPool {
-    // into pathbuf
-    path: String,
-    readers: HashMap<String, BufReader<File>>,
-}
+    // Records reach the OS as soon as they're written, but only reach the
+    // disk when it decides to. With an interval set, a background thread
+    // syncs the active log file that often, bounding what a crash of the
+    // machine can lose without syncing on every write. Files are synced one
+    // last time when they're sealed, by a rotation, a compaction or a
+    // reopen, and never written again, so the active one is all it syncs.
+    // Can't be combined with `flush_interval`: the thread only syncs what
+    // reached the file, not records still coalesced in memory
+    pub fn sync_interval(mut self, interval: Duration) -> KvStoreOptions {
+        self.sync_interval = Some(interval);
+        self
+    }
 
-impl ReaderPool {
-    fn new(path: impl Into<PathBuf>) -> ReaderPool {
-        let path = path.into();
+    // Coalesces records in memory and writes them out together, once the
+    // buffer fills up or a write finds the oldest buffered record is
+    // `interval` old. Reads, barriers and compactions write them out first.
+    // Nothing checks the interval between writes, so an idle store keeps
+    // its last records buffered until one of those or closing the store.
+    // Meant for bursts of small writes: records still in the buffer are
+    // lost if the process dies. Off by default, each record is written out
+    // on its own
+    pub fn flush_interval(mut self, interval: Duration) -> KvStoreOptions {
+        self.flush_interval = Some(interval);
+        self
+    }
 
-        let mut readers = HashMap::new();
-        let log_files = list_log_files(&path).unwrap();
+    // Bounds what `flush_interval` holds back to `bytes` of records, 8 KiB
+    // by default. A write taking them past it flushes them right away,
+    // blocking until the file has taken them, so a disk slower than the
+    // writes slows the writes down instead of letting records pile up
+    pub fn max_unflushed_bytes(mut self, bytes: usize) -> KvStoreOptions {
+        self.max_unflushed_bytes = Some(bytes);
+        self
+    }
 
-        for file_path in log_files {
-            let file_name = file_path.file_name().unwrap().to_str().unwrap();
-            let file = File::open(file_path.clone()).unwrap();
-            let reader = BufReader::new(file);
-            readers.insert(file_name.to_string(), reader);
-        }
+    // Also compacts, on open or on a write, once there are more than `max`
+    // log files, however little of them is dead. Bounds the recovery time
+    // of stores whose files pile up from frequent restarts or crashes. Live
+    // data that doesn't fit in `max` files is left in as few as it fits in.
+    // Off by default
+    pub fn max_generations(mut self, max: usize) -> KvStoreOptions {
+        self.max_generations = Some(max);
+        self
+    }
 
-        ReaderPool {
-            path: path.to_str().unwrap().to_string(),
-            readers: readers,
-        }
+    // Compacts on a thread of its own rather than in the write that
+    // triggers it. That write hands every log file so far over to the
+    // thread and carries on in a new active file, syncing the one it seals.
+    // The thread replays the files it was handed to tell live records from
+    // dead ones, and stages the compacted files. The first write to find it
+    // done commits them, renaming, syncing and deleting files but copying
+    // no records. So no write waits for records to be rewritten, but those
+    // two still pay for a sync and a few file operations, and dropping the
+    // store waits for a running pass. Off by default
+    pub fn background_compaction(mut self, enabled: bool) -> KvStoreOptions {
+        self.background_compaction = enabled;
+        self
     }
 
-    fn add_reader(&mut self, file_name: String) {
-        let file = File::open(format!("{}/{}", self.path, file_name)).unwrap();
-        let reader = BufReader::new(file);
-        self.readers.insert(file_name, reader);
+    // Never compacts on its own, for logs where every version has to be
+    // kept: overwritten values and tombstones stay on disk for good, and
+    // the active file just rolls over at the compaction threshold. The
+    // directory grows with every write, so it's on the caller to make room.
+    // Explicit calls like `compact_key` still rewrite what they're asked to
+    pub fn disable_compaction(mut self) -> KvStoreOptions {
+        self.compaction_disabled = true;
+        self
     }
 
-    fn get_reader(&mut self, file_name: String) -> &mut BufReader<File> {
-        self.readers.get_mut(&file_name).unwrap()
+    // Repeats every write on a second store in `path`, opened with the same
+    // options, as a warm copy. Only writes made while mirroring are
+    // repeated, so the mirror should start out as a copy of the store, e.g.
+    // both empty. A mirror that fails to open or to take a write is logged
+    // as a warning and otherwise ignored, see `mirror_errors_fail_writes`
+    pub fn mirror(mut self, path: impl Into<PathBuf>) -> KvStoreOptions {
+        self.mirror = Some(path.into());
+        self
     }
 
-    fn reader_list(&self) -> Vec<String> {
-        self.readers.keys().cloned().collect()
+    // Fails opening the store, or a write already made to it, when the
+    // mirror fails, instead of only logging it
+    pub fn mirror_errors_fail_writes(mut self, enabled: bool) -> KvStoreOptions {
+        self.mirror_errors_fail_writes = enabled;
+        self
     }
 
-    fn remove_readers(&mut self, file_names: Vec<String>) {
-        for file_name in file_names {
-            match fs::remove_file(format!("{}/{}", self.path, file_name)) {
-                Ok(_) => {}
-                Err(e) => {
-                    panic!("Error removing file: {}", e.to_string());
-                }
-            }
+    // How long opening for writing waits for another writer to let go of
+    // the directory before failing with `KvSError::Locked`. Fails right
+    // away by default
+    pub fn lock_timeout(mut self, timeout: Duration) -> KvStoreOptions {
+        self.lock_timeout = Some(timeout);
+        self
+    }
 
-            self.readers.remove(&file_name);
+    // How records are serialized, JSON by default
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> KvStoreOptions {
+        self.codec = Some(codec);
+        self
+    }
+
+    fn log_files(&self) -> LogFiles {
+        LogFiles {
+            layout: self.layout,
+            prefix: self
+                .log_file_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LOG_FILE_PREFIX.to_string()),
+            extension: self
+                .log_file_extension
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LOG_FILE_EXTENSION.to_string()),
         }
     }
 
-    fn read_from_pos_to_eol(&mut self, log_position: &LogPosition) -> Result<String, Error> {
-        let pos = log_position.pos;
-        let file_name = log_position.log_file_name.clone();
+    fn codec_or_default(&self) -> Arc<dyn Codec> {
+        self.codec.clone().unwrap_or_else(|| Arc::new(JsonCodec))
+    }
 
-        let reader = self.get_reader(file_name);
+    fn clock_or_default(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+}
 
-        reader.seek(SeekFrom::Start(pos))?;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    // Gets of live keys served from the value cache
+    pub cache_hits: u64,
+    // Gets of live keys that had to read the log
+    pub cache_misses: u64,
+    // Records read by compactions, each of them either kept or dropped
+    pub compaction_records_scanned: u64,
+    pub compaction_records_kept: u64,
+    pub compaction_records_dropped: u64,
+    // Size of the records compactions rewrote, headers included
+    pub compaction_bytes_rewritten: u64,
+}
 
-        let mut line = String::new();
+// Snapshot of the store's state, see `KvStore::health`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Health {
+    pub live_keys: usize,
+    pub log_files: usize,
+    // Total size of the log files in bytes
+    pub disk_usage: u64,
+    // Estimate of what a compaction would free, see `compaction_dead_ratio`
+    pub reclaimable_bytes: u64,
+    pub last_compaction_at: Option<DateTime<Utc>>,
+    // Whether writes were made since the last `barrier`, which a crash of
+    // the machine may lose. Always false for read-only stores
+    pub unsynced_writes: bool,
+}
 
-        // Read characters until the newline is found:
-        loop {
-            let mut buf = [0; 1]; // Buffer to hold a single character
-            let bytes_read = reader.read(&mut buf)?;
+// A value and where it lives, see `KvStore::get_with_metadata`. Writes carry
+// no timestamps, so there is none to report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueWithMeta {
+    pub value: String,
+    // Log file holding the record, relative to its bucket with
+    // `LogLayout::Nested`
+    pub log_file_name: String,
+    // Offset of the record in the file
+    pub pos: u64,
+    // Size of the record, header included
+    pub size: u64,
+    // Sequence number of the write, 0 for records written before they
+    // were numbered
+    pub seq: u64,
+}
 
-            if bytes_read == 0 {
-                // End of file reached
-                break;
-            }
+// Where `set_with_ref` wrote a value, for reading it back with `get_ref`.
+// Outlives overwrites and removes of the key, but not a rewrite of the
+// log files
+#[derive(Clone, Debug)]
+pub struct ValueRef {
+    position: LogPosition,
+    rewrites: u64,
+}
 
-            if buf[0] == b'\n' {
-                // Newline found, end of line reached
-                break;
-            }
+// Read-only view of the store as of `KvStore::snapshot`, which later writes
+// don't show up in. It holds its own handles to the log files it reads, so
+// files deleted by a compaction stay readable, and their disk space taken,
+// until it's dropped
+pub struct Snapshot {
+    map: BTreeMap<String, LogPosition>,
+    files: HashMap<String, File>,
+    codec: Arc<dyn Codec>,
+}
 
-            line.push(buf[0] as char);
+impl Snapshot {
+    pub fn get(&self, key: String) -> CommandResult<Option<String>> {
+        let log_position = match self.map.get(&key) {
+            Some(log_position) => log_position,
+            None => return Ok(None),
+        };
+
+        let file = &self.files[&log_position.log_file_name];
+        match read_command_log_from(file, log_position, self.codec.as_ref())
+            .with_context(|_| format!("Failed to get key `{}`", key))?
+        {
+            (_, CommandLog::Set { value, .. }) => Ok(Some(value)),
+            (_, CommandLog::Remove { .. }) => Ok(None),
         }
-
-        Ok(line)
     }
 }
 
-struct NamedBufWriter {
-    writer: BufWriter<File>,
-    file_name: String,
+pub struct KvStore {
+    path: PathBuf,
+    options: KvStoreOptions,
+    files: LogFiles,
+    codec: Arc<dyn Codec>,
+    key_dir: KeyDir,
+    // None when opened read-only
+    writer_pool: Option<WriterPool>,
+    reader_pool: ReaderPool,
+    value_cache: ValueCache,
+    stats: Stats,
+    compactions: u64,
+    // Bumped whenever records may have moved, invalidating every ValueRef
+    rewrites: u64,
+    background_compaction: Option<BackgroundCompaction>,
+    // See `KvStoreOptions::mirror`, None when it failed to open
+    mirror: Option<Box<KvStore>>,
+    last_compaction_at: Option<DateTime<Utc>>,
+    // Locked file keeping other writers out, None when opened read-only
+    lock: Option<File>,
+    // Set for stores from `open_in_memory`. Last, so it's only removed once
+    // every file in it has been closed
+    temp_dir: Option<TempDir>,
 }
 
-impl NamedBufWriter {
-    fn new(path: impl Into<PathBuf>, file_name: String) -> NamedBufWriter {
-        NamedBufWriter {
-            writer: BufWriter::new(
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .append(true)
-                    .open(path.into().join(file_name.clone()))
-                    .unwrap(),
-            ),
-            file_name,
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> CommandResult<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    // A store in a fresh temporary directory, which is deleted when the
+    // store is dropped. It otherwise behaves like one from `open`
+    pub fn open_in_memory() -> CommandResult<KvStore> {
+        let temp_dir = TempDir::new()?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.temp_dir = Some(temp_dir);
+
+        Ok(store)
+    }
+
+    // Opens the store and, if it has no live keys, sets every pair of
+    // `seed` in one buffered pass. Holding the directory lock from the
+    // check through the seeding keeps two processes from both seeding it
+    pub fn open_or_create_with_seed(
+        path: impl Into<PathBuf>,
+        seed: impl IntoIterator<Item = (String, String)>,
+    ) -> CommandResult<KvStore> {
+        let mut store = KvStore::open(path)?;
+        if store.key_dir.map.is_empty() {
+            store.set_all(seed)?;
         }
+
+        Ok(store)
+    }
+
+    // Refuses to open a directory written by a different engine, as every
+    // open does. Use `verify_engine_marker` to check for another engine
+    pub fn open_with_engine_marker_check(path: impl Into<PathBuf>) -> CommandResult<KvStore> {
+        KvStore::open(path)
     }
 
-    fn write(&mut self, s: String) -> Result<LogPosition, Error> {
-        let writer = &mut self.writer;
-        writeln!(writer, "{}", s)?;
+    // Waits up to `timeout` for another writer to close the directory,
+    // e.g. the old process during a restart
+    pub fn open_with_lock_timeout(
+        path: impl Into<PathBuf>,
+        timeout: Duration,
+    ) -> CommandResult<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::new().lock_timeout(timeout))
+    }
 
-        let start_pos = writer.stream_position()? - s.len() as u64 - 1;
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> CommandResult<KvStore> {
+        let path = path.into();
+        if options.sync_interval.is_some() && options.flush_interval.is_some() {
+            return Err(failure::err_msg(
+                "sync_interval can't be combined with flush_interval",
+            ));
+        }
 
-        Ok(LogPosition {
-            pos: start_pos,
-            log_file_name: self.file_name.clone(),
-        })
+        // Create directory if it doesn't exist
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path, options.lock_timeout)?;
+
+        KvStore::open_locked(path, options, lock)
     }
 
-    fn sync(&mut self) -> Result<(), Error> {
-        self.writer.flush()?;
-        Ok(())
+    // Opens the store in `path` for writing, with `lock` already held
+    fn open_locked(path: PathBuf, options: KvStoreOptions, lock: File) -> CommandResult<KvStore> {
+        let files = options.log_files();
+        let codec = options.codec_or_default();
+        check_engine_marker(&path, &files, codec.as_ref())?;
+        recover_compaction(&path, &files)?;
+
+        // Initialize map with command logs from previous sessions
+        let mut key_dir = KeyDir::init_with_command_logs(
+            &path,
+            &files,
+            codec.as_ref(),
+            options.recovery_threads.unwrap_or(1),
+        )?;
+        if let Some(marker) = read_engine_marker(&path)? {
+            key_dir.last_seq = key_dir.last_seq.max(marker.last_seq);
+        }
+        let mut writer_pool = WriterPool::new(
+            &path,
+            &files,
+            options.initial_generation.unwrap_or(1),
+            options.flush_interval,
+            options.max_unflushed_bytes,
+        )?;
+        if let Some(interval) = options.sync_interval {
+            writer_pool.start_background_sync(interval);
+        }
+        let writer_pool = Some(writer_pool);
+        let reader_pool = ReaderPool::new(&path, &files, options.max_open_readers, false);
+        let value_cache = ValueCache::new(options.value_cache_capacity, options.on_evict.clone());
+
+        let mut store = KvStore {
+            path,
+            options,
+            files,
+            codec,
+            key_dir,
+            writer_pool,
+            reader_pool,
+            value_cache,
+            stats: Stats::default(),
+            compactions: 0,
+            rewrites: 0,
+            background_compaction: None,
+            mirror: None,
+            last_compaction_at: None,
+            lock: Some(lock),
+            temp_dir: None,
+        };
+        if !store.options.compaction_disabled && store.too_many_generations() {
+            store.compact_log_files()?;
+        }
+
+        if let Some(mirror_path) = store.options.mirror.clone() {
+            let mut options = store.options.clone();
+            options.mirror = None;
+            match KvStore::open_with_options(&mirror_path, options) {
+                Ok(mirror) => store.mirror = Some(Box::new(mirror)),
+                Err(e) if store.options.mirror_errors_fail_writes => return Err(e),
+                Err(e) => {
+                    warn!(mirror = %mirror_path.display(), error = %e, "Failed to open mirror")
+                }
+            }
+        }
+
+        Ok(store)
     }
-}
 
-fn list_log_files(path: impl Into<PathBuf>) -> Result<Vec<PathBuf>, Error> {
-    // Read directory entries
-    let entries = fs::read_dir(path.into())?
-        .filter_map(|entry| entry.ok())
-        .collect::<Vec<_>>();
+    // Opens an existing store without writing anything to its directory, so
+    // it also works on read-only file systems. Mutations fail with
+    // `KvSError::ReadOnly`. Only one store at a time can have a directory
+    // open for writing, but any number can open it read-only next to it
+    // and `reload` to follow its writes
+    pub fn open_read_only(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> CommandResult<KvStore> {
+        let path = path.into();
+        let files = options.log_files();
+
+        let codec = options.codec_or_default();
+        let marker = read_engine_marker(&path)?;
+        check_codec(&path, &files, marker.as_ref(), codec.as_ref())?;
+        check_layout(marker.as_ref(), &files)?;
+        if path.join(COMPACTION_COMMITTED_DIR_NAME).exists() {
+            return Err(failure::err_msg(
+                "Unfinished compaction, open the store for writing to recover it",
+            ));
+        }
 
-    // Find files with .cmdlog extension
-    let mut log_files: Vec<_> = entries
-        .iter()
-        .filter(|entry| entry.path().is_file())
-        .filter(|entry| {
-            entry
-                .path()
-                .extension()
-                .map_or(false, |ext| ext == "cmdlog")
+        let mut key_dir = KeyDir::init_with_command_logs(
+            &path,
+            &files,
+            codec.as_ref(),
+            options.recovery_threads.unwrap_or(1),
+        )?;
+        if let Some(marker) = marker {
+            key_dir.last_seq = key_dir.last_seq.max(marker.last_seq);
+        }
+        let reader_pool = ReaderPool::new(&path, &files, options.max_open_readers, true);
+        let value_cache = ValueCache::new(options.value_cache_capacity, options.on_evict.clone());
+
+        Ok(KvStore {
+            path,
+            options,
+            files,
+            codec,
+            key_dir,
+            writer_pool: None,
+            reader_pool,
+            value_cache,
+            stats: Stats::default(),
+            compactions: 0,
+            rewrites: 0,
+            background_compaction: None,
+            mirror: None,
+            last_compaction_at: None,
+            lock: None,
+            temp_dir: None,
         })
-        .map(|entry| entry.path())
-        .collect();
+    }
 
-    log_files.sort();
+    // Re-scans the log files to pick up records appended by another process.
+    // Meant for read-only consumers: any compaction done elsewhere, or
+    // writes from this store racing with the other writer, can leave the
+    // rebuilt KeyDir pointing at files that no longer exist
+    pub fn reload(&mut self) -> CommandResult<()> {
+        self.sync_writer()?;
+        self.finish_background_compaction()?;
+
+        self.key_dir = KeyDir::init_with_command_logs(
+            &self.path,
+            &self.files,
+            self.codec.as_ref(),
+            self.options.recovery_threads.unwrap_or(1),
+        )?;
+        self.reader_pool = ReaderPool::new(
+            &self.path,
+            &self.files,
+            self.options.max_open_readers,
+            self.writer_pool.is_none(),
+        );
+        self.value_cache.clear();
+        self.rewrites += 1;
 
-    Ok(log_files)
-}
+        Ok(())
+    }
 
-fn new_log_file_name() -> String {
-    format!(
-        "{}_{}.{}",
-        LOG_FILE_PREFIX,
-        Utc::now().timestamp_nanos_opt().unwrap(),
-        LOG_FILE_EXTENSION
-    )
-}
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-fn latest_log_file_metadata(path: impl Into<PathBuf>) -> Result<(String, u64), Error> {
-    let log_files = list_log_files(path)?;
-    if log_files.is_empty() {
-        return Err(failure::err_msg("No log files found"));
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // Zeroes the stats and the compaction counter, e.g. between benchmark
+    // runs. Only touches those counters: nothing on disk changes,
+    // `last_compaction_at` is kept and a mirror keeps its own stats
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.compactions = 0;
+    }
+
+    // Summary of the store for monitoring. Cheap, reading nothing from disk
+    // but the sizes of the log files, which leave out records `flush_interval`
+    // still holds back
+    pub fn health(&self) -> CommandResult<Health> {
+        Ok(Health {
+            live_keys: self.key_dir.map.len(),
+            log_files: self.reader_pool.file_names.len(),
+            disk_usage: self.disk_usage()?,
+            reclaimable_bytes: self.key_dir.dead_bytes,
+            last_compaction_at: self.last_compaction_at,
+            unsynced_writes: self
+                .writer_pool
+                .as_ref()
+                .is_some_and(|writer_pool| writer_pool.unsynced_writes),
+        })
+    }
+
+    // Total size of the log files in bytes. Records `flush_interval` still
+    // holds back aren't counted until they're flushed
+    pub fn disk_usage(&self) -> CommandResult<u64> {
+        let mut size = 0;
+        for file_path in list_log_files(&self.path, &self.files)? {
+            size += file_path.metadata()?.len();
+        }
+
+        Ok(size)
+    }
+
+    // Number of compactions run since the store was opened or its stats
+    // were last reset
+    pub fn compactions(&self) -> u64 {
+        self.compactions
+    }
+
+    pub fn last_compaction_at(&self) -> Option<DateTime<Utc>> {
+        self.last_compaction_at
+    }
+
+    pub fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        Ok(self.get_arc(key)?.map(|value| value.to_string()))
+    }
+
+    // Like `get`, but values served from the value cache are shared with
+    // it instead of copied
+    pub fn get_arc(&mut self, key: String) -> CommandResult<Option<Arc<str>>> {
+        self.sync_writer()?;
+
+        let res = self.key_dir.get(&key);
+        match res {
+            Some(log_pos) => {
+                if let Some(value) = self.value_cache.get(&key) {
+                    self.stats.cache_hits += 1;
+                    return Ok(Some(value));
+                }
+                self.stats.cache_misses += 1;
+
+                let (_, command_log) = self
+                    .reader_pool
+                    .read_command_log(log_pos, self.codec.as_ref())
+                    .with_context(|_| format!("Failed to get key `{}`", key))?;
+                match command_log {
+                    CommandLog::Set { value, .. } => {
+                        let value: Arc<str> = value.into();
+                        self.value_cache.insert(key, Arc::clone(&value));
+                        Ok(Some(value))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Like `get`, but also tells where the value lives in the log and the
+    // sequence number of the write that set it. Always reads the log,
+    // bypassing the value cache
+    pub fn get_with_metadata(&mut self, key: String) -> CommandResult<Option<ValueWithMeta>> {
+        self.sync_writer()?;
+
+        let log_pos = match self.key_dir.get(&key) {
+            Some(log_pos) => log_pos,
+            None => return Ok(None),
+        };
+        let (seq, command_log) = self
+            .reader_pool
+            .read_command_log(log_pos, self.codec.as_ref())
+            .with_context(|_| format!("Failed to get key `{}`", key))?;
+        match command_log {
+            CommandLog::Set { value, .. } => Ok(Some(ValueWithMeta {
+                value,
+                log_file_name: log_pos.log_file_name.clone(),
+                pos: log_pos.pos,
+                size: log_pos.size,
+                seq,
+            })),
+            CommandLog::Remove { .. } => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        self.set_with_ref(key, value).map(drop)
+    }
+
+    // Captures the live keys as they are now, for consistent reads across
+    // several keys while writes carry on. Copies the KeyDir, so it costs
+    // time and memory in proportion to the number of keys
+    pub fn snapshot(&mut self) -> CommandResult<Snapshot> {
+        self.sync_writer()?;
+
+        let mut files = HashMap::new();
+        for file_name in self.reader_pool.reader_list() {
+            let file = File::open(self.files.log_file_path(&self.path, &file_name))?;
+            files.insert(file_name, file);
+        }
+
+        Ok(Snapshot {
+            map: self.key_dir.map.clone(),
+            files,
+            codec: Arc::clone(&self.codec),
+        })
+    }
+
+    // Like `set`, also returning a reference `get_ref` can read the value
+    // back through without looking up the key, e.g. for secondary indexes
+    pub fn set_with_ref(&mut self, key: String, value: String) -> CommandResult<ValueRef> {
+        self.check_key(&key)?;
+
+        let mut pos = self.write_command_log(CommandLog::Set {
+            key: key.clone(),
+            value: value.clone(),
+        })?;
+        pos.value_len = value.len();
+
+        self.value_cache.remove(&key);
+        self.key_dir.set(key.clone(), pos.clone());
+        self.mirror(|mirror| mirror.set(key, value))?;
+
+        Ok(ValueRef {
+            position: pos,
+            rewrites: self.rewrites,
+        })
+    }
+
+    // The value written when `value_ref` was returned, even if the key has
+    // been overwritten or removed since. Fails with
+    // `KvSError::StaleValueRef` once a compaction may have moved it
+    pub fn get_ref(&mut self, value_ref: &ValueRef) -> CommandResult<String> {
+        if value_ref.rewrites != self.rewrites {
+            return Err(KvSError::StaleValueRef.into());
+        }
+        self.sync_writer()?;
+
+        match self
+            .reader_pool
+            .read_command_log(&value_ref.position, self.codec.as_ref())?
+        {
+            (_, CommandLog::Set { value, .. }) => Ok(value),
+            (_, CommandLog::Remove { .. }) => Err(KvSError::StaleValueRef.into()),
+        }
+    }
+
+    // Returns once every write made before it is on disk. Writes otherwise
+    // only reach the disk when the OS or `sync_interval` gets to them, so
+    // writers that can wait for durability call this once per group of
+    // writes instead of paying for a sync on each of them
+    pub fn barrier(&mut self) -> CommandResult<()> {
+        if let Some(writer_pool) = self.writer_pool.as_mut() {
+            writer_pool.barrier()?;
+        }
+
+        self.mirror(KvStore::barrier)
+    }
+
+    // Shuts the store down, returning the errors dropping it would swallow.
+    // Finishes a running background compaction and waits for every write to
+    // reach the disk, as `barrier` does. The directory lock is released once
+    // it returns, whether or not it succeeded
+    pub fn close(mut self) -> CommandResult<()> {
+        self.finish_background_compaction()?;
+        self.barrier()
+    }
+
+    // Closes the store, as `close` does, moves its directory to `dest` and
+    // opens it there with the same options. `dest` must not exist yet.
+    // Moves across file systems copy the directory and sync the copy before
+    // deleting the original. A store from `open_in_memory` stays where it's
+    // moved to, it's no longer deleted when dropped
+    pub fn move_to(mut self, dest: impl Into<PathBuf>) -> CommandResult<KvStore> {
+        let dest = dest.into();
+        if dest.exists() {
+            return Err(failure::format_err!("{} already exists", dest.display()));
+        }
+        // Held until the store is open again, so no other writer can get in
+        // between. Renaming the directory moves the locked file along
+        let mut lock = match self.lock.take() {
+            Some(lock) => lock,
+            None => return Err(failure::err_msg("Read-only stores can't be moved")),
+        };
+
+        let path = self.path.clone();
+        let options = self.options.clone();
+        // Removes the directory as dropping the store would, unless it's
+        // been moved
+        let temp_dir = self.temp_dir.take();
+        self.close()?;
+
+        match fs::rename(&path, &dest) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                copy_dir(&path, &dest)?;
+                lock = lock_dir(&dest, None)?;
+                fs::remove_dir_all(&path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(temp_dir) = temp_dir {
+            let _ = temp_dir.keep();
+        }
+        if let Some(parent) = dest
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            sync_dir(parent)?;
+        }
+
+        KvStore::open_locked(dest, options, lock)
+    }
+
+    // Writes one line per record in the log, oldest first: file, position,
+    // length, sequence number, whether the KeyDir still points at it, and
+    // the decoded command. Meant for inspecting the log format by hand
+    pub fn debug_dump(&mut self, mut writer: impl Write) -> CommandResult<()> {
+        self.sync_writer()?;
+        for file_path in list_log_files(&self.path, &self.files)? {
+            let file_name = file_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned();
+            let mut reader = BufReader::new(File::open(&file_path)?);
+
+            let mut pos = 0;
+            while let Some((payload, size)) = read_record(&mut reader)? {
+                let (seq, command) = self.codec.decode(&payload)?;
+                let live = match &command {
+                    CommandLog::Set { key, .. } => self.key_dir.get(key).is_some_and(|log_pos| {
+                        log_pos.log_file_name == file_name && log_pos.pos == pos
+                    }),
+                    CommandLog::Remove { .. } => false,
+                };
+                writeln!(
+                    writer,
+                    "{} pos={} len={} seq={} {} {:?}",
+                    file_name,
+                    pos,
+                    size,
+                    seq,
+                    if live { "live" } else { "dead" },
+                    command
+                )?;
+                pos += size;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes committed after `seq`, oldest first, for a follower to `apply`.
+    // Compaction only keeps the latest write of each live key, and
+    // tombstones only within the retention window, so a follower must keep
+    // up with the compactions to see every remove
+    pub fn changes_since(
+        &mut self,
+        seq: u64,
+    ) -> CommandResult<impl Iterator<Item = (u64, CommandLog)>> {
+        self.sync_writer()?;
+        let mut changes = Vec::new();
+        for file_path in list_log_files(&self.path, &self.files)? {
+            let mut reader = BufReader::new(File::open(file_path)?);
+            while let Some((payload, _)) = read_record(&mut reader)? {
+                let (record_seq, command) = self.codec.decode(&payload)?;
+                if record_seq > seq {
+                    changes.push((record_seq, command));
+                }
+            }
+        }
+        changes.sort_by_key(|(seq, _)| *seq);
+
+        Ok(changes.into_iter())
+    }
+
+    // Applies a change read from another store's `changes_since`
+    pub fn apply(&mut self, change: CommandLog) -> CommandResult<()> {
+        match change {
+            CommandLog::Set { key, value } => self.set(key, value),
+            CommandLog::Remove { key, .. } => {
+                if self.key_dir.contains_key(&key) {
+                    self.remove(key)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Iterates live keys in sorted order, starting from the first key
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor {
+            store: self,
+            next_key: Bound::Unbounded,
+        }
+    }
+
+    // Returns the live entries, in key order, for which `pred` holds. Values
+    // are read one at a time as the keys are walked
+    pub fn filter(&mut self, pred: impl Fn(&str, &str) -> bool) -> CommandResult<Vec<Entry>> {
+        let mut matches = Vec::new();
+        for entry in self.cursor() {
+            let entry = entry?;
+            if pred(&entry.key, &entry.value) {
+                matches.push(entry);
+            }
+        }
+
+        Ok(matches)
+    }
+
+    // Writes a compacted copy of the store to `dest`, which must not hold a
+    // store yet: the live values only, in log files of the same codec and
+    // layout, leaving this store's files untouched. The copy keeps no
+    // tombstones, it has no older values for them to hide
+    pub fn compact_into(&mut self, dest: impl Into<PathBuf>) -> CommandResult<()> {
+        let dest = dest.into();
+        fs::create_dir_all(&dest)?;
+        let _lock = lock_dir(&dest, None)?;
+        if !list_log_files(&dest, &self.files)?.is_empty() {
+            return Err(failure::format_err!(
+                "{} already holds a store",
+                dest.display()
+            ));
+        }
+        check_engine_marker(&dest, &self.files, self.codec.as_ref())?;
+
+        self.sync_writer()?;
+        let live = LiveRecords {
+            map: Cow::Borrowed(&self.key_dir.map),
+            tombstone_cutoff: None,
+            keep_tombstones_in: BTreeSet::new(),
+        };
+        let first_generation = self.options.initial_generation.unwrap_or(1);
+        let staged = stage_rewrite(
+            &self.path,
+            &dest,
+            &self.files,
+            self.codec.as_ref(),
+            &self.reader_pool.reader_list(),
+            &live,
+            first_generation..u64::MAX,
+        )?;
+        let manifest: Vec<&String> = staged.compacted_files.iter().collect();
+        commit_staged_files(&dest, &self.files, &manifest, self.key_dir.last_seq)?;
+
+        Ok(())
+    }
+
+    // Writes every live entry as a line of JSON, in key order. Entries are
+    // read and written one at a time, so the store never has to fit in
+    // memory
+    pub fn export(&mut self, mut writer: impl Write) -> CommandResult<()> {
+        for entry in self.cursor() {
+            serde_json::to_writer(&mut writer, &entry?)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Sets every entry read from the output of `export`, one line at a
+    // time, returning how many were set
+    pub fn import(&mut self, reader: impl BufRead) -> CommandResult<usize> {
+        let mut count = 0;
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let entry: Entry = serde_json::from_str(&line)
+                .with_context(|_| format!("Failed to import line {}", line_number + 1))?;
+            self.set(entry.key, entry.value)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // Writes the value only if `key` has no value yet, returning whether it
+    // was inserted
+    pub fn set_if_absent(&mut self, key: String, value: String) -> CommandResult<bool> {
+        if self.key_dir.contains_key(&key) {
+            return Ok(false);
+        }
+
+        self.set(key, value)?;
+
+        Ok(true)
+    }
+
+    // Bulk insert for loading large datasets. Records are written in one
+    // buffered pass with a single flush, compaction is only considered
+    // once all of them are written
+    pub fn set_all(
+        &mut self,
+        items: impl IntoIterator<Item = (String, String)>,
+    ) -> CommandResult<()> {
+        let items: Vec<(String, String)> = items.into_iter().collect();
+        for (key, _) in items.iter() {
+            self.check_key(key)?;
+        }
+        let mirrored_items = self.mirror.is_some().then(|| items.clone());
+
+        let mut keys = Vec::with_capacity(items.len());
+        let mut serialized_logs = Vec::with_capacity(items.len());
+        for (key, value) in items {
+            let value_len = value.len();
+            serialized_logs.push(self.encode_command(CommandLog::Set {
+                key: key.clone(),
+                value,
+            })?);
+            keys.push((key, value_len));
+        }
+
+        let positions = self.writable()?.write_all(serialized_logs)?;
+        for ((key, value_len), mut pos) in keys.into_iter().zip(positions) {
+            pos.value_len = value_len;
+            self.value_cache.remove(&key);
+            self.key_dir.set(key, pos);
+        }
+
+        self.writable()?.sync()?;
+
+        self.compact_if_needed(0)?;
+
+        if let Some(items) = mirrored_items {
+            self.mirror(|mirror| mirror.set_all(items))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: String) -> CommandResult<()> {
+        self.check_key(&key)?;
+
+        if !self.key_dir.contains_key(&key) {
+            if self.options.idempotent_remove {
+                return Ok(());
+            }
+            return Err(KvSError::KeyNotFound { key }.into());
+        }
+
+        let removed_at = self.tombstone_timestamp();
+        let tombstone = self.write_command_log(CommandLog::Remove {
+            key: key.clone(),
+            removed_at,
+        })?;
+
+        self.value_cache.remove(&key);
+        self.key_dir.remove(&key, tombstone.size);
+        self.mirror(|mirror| mirror.remove_many(&[key]).map(drop))?;
+
+        Ok(())
+    }
+
+    // Writes the current value of `key` again at the end of the active log
+    // file, so all of the key's older records are dead. For keys that are
+    // overwritten a lot, whose live record would otherwise keep an old file
+    // from being reclaimed until the next compaction rewrites it
+    pub fn compact_key(&mut self, key: &str) -> CommandResult<()> {
+        let value = match self.get(key.to_string())? {
+            Some(value) => value,
+            None => {
+                return Err(KvSError::KeyNotFound {
+                    key: key.to_string(),
+                }
+                .into())
+            }
+        };
+
+        let value_len = value.len();
+        let mut pos = self.write_command_log(CommandLog::Set {
+            key: key.to_string(),
+            value,
+        })?;
+        pos.value_len = value_len;
+        self.key_dir.set(key.to_string(), pos);
+
+        Ok(())
+    }
+
+    // Length in bytes of the value of `key`, kept in the KeyDir so no record
+    // has to be read
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        self.key_dir.get(key).map(|log_pos| log_pos.value_len)
+    }
+
+    // How many live keys start with `prefix`. Only the KeyDir is walked, no
+    // values are read
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        self.key_dir.with_prefix(prefix).count()
+    }
+
+    // Live keys matching the glob `pattern`, in key order: `*` matches any
+    // run of characters, `?` exactly one. Only keys starting with the part
+    // before the first wildcard are looked at
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        let prefix = &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())];
+        self.key_dir
+            .with_prefix(prefix)
+            .map(|(key, _)| key)
+            .filter(|key| glob_matches(pattern, key))
+            .cloned()
+            .collect()
+    }
+
+    // Removes every key starting with `prefix`, returning how many were
+    // removed. All tombstones are written in one buffered pass
+    pub fn delete_prefix(&mut self, prefix: &str) -> CommandResult<usize> {
+        let keys = self.key_dir.keys_with_prefix(prefix);
+        self.remove_existing(keys)
+    }
+
+    // Removes the given keys, returning how many of them existed. Missing
+    // keys are skipped. All tombstones are written in one buffered pass
+    pub fn remove_many(&mut self, keys: &[String]) -> CommandResult<usize> {
+        let keys: BTreeSet<String> = keys
+            .iter()
+            .filter(|key| self.key_dir.contains_key(key))
+            .cloned()
+            .collect();
+        self.remove_existing(keys.into_iter().collect())
+    }
+
+    // Removes every key for which `keep` doesn't hold, returning how many
+    // were removed. Every live value is read to decide, so this costs as
+    // much as a full scan. All tombstones are written in one buffered pass
+    pub fn retain(&mut self, keep: impl Fn(&str, &str) -> bool) -> CommandResult<usize> {
+        let keys = self
+            .filter(|key, value| !keep(key, value))?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        self.remove_existing(keys)
+    }
+
+    // `keys` must all be live and distinct
+    fn remove_existing(&mut self, keys: Vec<String>) -> Result<usize, Error> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let removed_at = self.tombstone_timestamp();
+        let serialized_logs = keys
+            .iter()
+            .map(|key| {
+                self.encode_command(CommandLog::Remove {
+                    key: key.clone(),
+                    removed_at,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let tombstones = self.writable()?.write_all(serialized_logs)?;
+
+        for (key, tombstone) in keys.iter().zip(tombstones) {
+            self.value_cache.remove(key);
+            self.key_dir.remove(key, tombstone.size);
+        }
+
+        self.compact_if_needed(0)?;
+
+        self.mirror(|mirror| mirror.remove_many(&keys).map(drop))?;
+
+        Ok(keys.len())
+    }
+
+    // Repeats a write already made to this store on the mirror, if any.
+    // Removes are mirrored with `remove_many`, which skips missing keys
+    fn mirror(
+        &mut self,
+        write: impl FnOnce(&mut KvStore) -> CommandResult<()>,
+    ) -> Result<(), Error> {
+        let mirror = match self.mirror.as_mut() {
+            Some(mirror) => mirror,
+            None => return Ok(()),
+        };
+
+        match write(mirror) {
+            Err(e) if self.options.mirror_errors_fail_writes => Err(e),
+            Err(e) => {
+                warn!(mirror = %mirror.path.display(), error = %e, "Failed to mirror a write");
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), Error> {
+        if key.is_empty() {
+            return Err(KvSError::KeyNotProvided.into());
+        }
+
+        match self.options.max_key_bytes {
+            Some(limit) if key.len() > limit => Err(KvSError::KeyTooLarge {
+                size: key.len(),
+                limit,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn writable(&mut self) -> Result<&mut WriterPool, Error> {
+        self.writer_pool
+            .as_mut()
+            .ok_or_else(|| KvSError::ReadOnly.into())
+    }
+
+    fn sync_writer(&mut self) -> Result<(), Error> {
+        match self.writer_pool.as_mut() {
+            Some(writer_pool) => writer_pool.sync(),
+            None => Ok(()),
+        }
+    }
+
+    // Serializes a write, giving it the next sequence number
+    fn encode_command(&mut self, command: CommandLog) -> Result<Vec<u8>, Error> {
+        self.key_dir.last_seq += 1;
+        self.codec.encode(self.key_dir.last_seq, &command)
+    }
+
+    // Records of `COMPACTION_THRESHOLD` bytes or more get a log file of their
+    // own, with writes carrying on in a fresh file after it. Left in the
+    // active file they would keep it over the threshold and compact the
+    // store on every following write
+    fn write_command_log(&mut self, command_log: CommandLog) -> Result<LogPosition, Error> {
+        let serialized_log = self.encode_command(command_log)?;
+        if serialized_log.len() < COMPACTION_THRESHOLD {
+            self.compact_if_needed(serialized_log.len())?;
+            return self.writable()?.write(serialized_log);
+        }
+
+        self.compact_if_needed(0)?;
+        if self.writable()?.active_size() > 0 {
+            self.roll_active()?;
+        }
+        let pos = self.writable()?.write(serialized_log)?;
+        self.roll_active()?;
+
+        Ok(pos)
+    }
+
+    fn roll_active(&mut self) -> Result<(), Error> {
+        let file_name = self.writable()?.roll()?;
+        self.reader_pool.add_reader(file_name);
+
+        Ok(())
+    }
+
+    // Compacts once the active file would grow past the threshold with
+    // `incoming` more bytes, once there are too many log files, or once the
+    // dead ratio set in the options is reached. The latter waits for a
+    // minimum of dead bytes, so small stores aren't rewritten for every
+    // overwrite
+    fn should_compact(&mut self, incoming: usize) -> Result<bool, Error> {
+        if self.writable()?.active_size() + incoming >= COMPACTION_THRESHOLD
+            || self.too_many_generations()
+        {
+            return Ok(true);
+        }
+
+        Ok(self.options.compaction_dead_ratio.is_some_and(|ratio| {
+            self.key_dir.dead_bytes >= MIN_DEAD_RATIO_COMPACTION_BYTES
+                && self.key_dir.dead_ratio() >= ratio
+        }))
+    }
+
+    // Live data too large for `max_generations` files would otherwise be
+    // compacted on every write, so the cap gives way to what a compaction
+    // can actually fit it in
+    fn too_many_generations(&self) -> bool {
+        let live_bytes = self
+            .key_dir
+            .total_bytes
+            .saturating_sub(self.key_dir.dead_bytes);
+        let compacted_files = live_bytes as usize / COMPACTION_THRESHOLD + 1;
+        self.options
+            .max_generations
+            .is_some_and(|max| self.reader_pool.file_names.len() > max.max(compacted_files))
+    }
+
+    // Merges every log file smaller than `size_floor` bytes, together with
+    // the active one, into as few files as fit under the compaction
+    // threshold, dropping their dead records. Crashes can leave behind many
+    // small files that never trigger a compaction on their own, each costing
+    // a file descriptor and a pass on recovery. Larger files are left alone
+    pub fn consolidate(&mut self, size_floor: u64) -> CommandResult<()> {
+        self.writable()?.sync()?;
+        self.finish_background_compaction()?;
+
+        // Merged files get the newest generations, so writes have to carry
+        // on after them and the active file is always merged
+        let active = self.writable()?.curr.clone();
+        let mut file_names = Vec::new();
+        for file_name in self.reader_pool.reader_list() {
+            let file_path = self.files.log_file_path(&self.path, &file_name);
+            if file_name == active || fs::metadata(file_path)?.len() < size_floor {
+                file_names.push(file_name);
+            }
+        }
+        if file_names.len() < 2 {
+            return Ok(());
+        }
+
+        self.rewrite_log_files(file_names)
+    }
+
+    // Commits a background compaction that's done, then compacts if needed
+    fn compact_if_needed(&mut self, incoming: usize) -> Result<(), Error> {
+        let finished = self
+            .background_compaction
+            .as_ref()
+            .is_some_and(|running| running.handle.is_finished());
+        if finished {
+            self.finish_background_compaction()?;
+        }
+
+        if self.options.compaction_disabled {
+            if self.writable()?.active_size() + incoming >= COMPACTION_THRESHOLD {
+                self.roll_active()?;
+            }
+        } else if self.should_compact(incoming)? {
+            self.start_compaction(incoming)?;
+        }
+
+        Ok(())
+    }
+
+    // Compacts inline, or with `background_compaction` hands the log over to
+    // a compaction thread. While one is running, writes only roll over to a
+    // new active file when theirs is full
+    fn start_compaction(&mut self, incoming: usize) -> Result<(), Error> {
+        if !self.options.background_compaction {
+            return self.compact_log_files();
+        }
+
+        if self.background_compaction.is_some() {
+            if self.writable()?.active_size() + incoming >= COMPACTION_THRESHOLD {
+                self.roll_active()?;
+            }
+            return Ok(());
+        }
+
+        // Writes carry on in a new active file. Its generation leaves room
+        // for the compacted files, which have to replay before it
+        let file_names = self.reader_pool.reader_list();
+        let reserved = 2 * self.key_dir.total_bytes / COMPACTION_THRESHOLD as u64
+            + file_names.len() as u64
+            + 2;
+        let writer_pool = self.writable()?;
+        let first_generation = writer_pool.last_generation + 1;
+        writer_pool.last_generation += reserved;
+        let file_name = writer_pool.roll()?;
+        self.reader_pool.add_reader(file_name);
+
+        let tombstone_cutoff = self.tombstone_cutoff();
+        let path = self.path.clone();
+        let files = self.files.clone();
+        let codec = Arc::clone(&self.codec);
+        let thread_file_names = file_names.clone();
+        let handle = thread::spawn(move || {
+            // Every file so far was handed over, so replaying them gives the
+            // KeyDir as it was, without the write copying it
+            let mut key_dir = KeyDir::new();
+            for file_name in &thread_file_names {
+                let file_path = files.log_file_path(&path, file_name);
+                key_dir.merge(FileScan::read(&file_path, codec.as_ref())?);
+            }
+            let live = LiveRecords {
+                map: Cow::Owned(key_dir.map),
+                tombstone_cutoff,
+                keep_tombstones_in: BTreeSet::new(),
+            };
+            stage_rewrite(
+                &path,
+                &path,
+                &files,
+                codec.as_ref(),
+                &thread_file_names,
+                &live,
+                first_generation..first_generation + reserved,
+            )
+        });
+        self.background_compaction = Some(BackgroundCompaction { file_names, handle });
+
+        Ok(())
+    }
+
+    // Waits for the running background compaction, if any, and commits it
+    fn finish_background_compaction(&mut self) -> Result<(), Error> {
+        let running = match self.background_compaction.take() {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+
+        let staged = running
+            .handle
+            .join()
+            .map_err(|_| failure::err_msg("Background compaction panicked"))??;
+        self.commit_rewrite(&running.file_names, staged)?;
+
+        self.compactions += 1;
+        self.last_compaction_at = Some(self.options.clock_or_default().now());
+
+        Ok(())
+    }
+
+    fn compact_log_files(&mut self) -> Result<(), Error> {
+        self.writable()?.sync()?;
+        let reader_list = self.reader_pool.reader_list();
+        self.rewrite_log_files(reader_list)?;
+        // Whatever is left, retained tombstones, stays until a later
+        // compaction can drop it
+        self.key_dir.dead_bytes = 0;
+
+        self.compactions += 1;
+        self.last_compaction_at = Some(self.options.clock_or_default().now());
+
+        Ok(())
+    }
+
+    // Rewrites the live records of `reader_list`, which must include the
+    // active file, and carries on writing after them
+    fn rewrite_log_files(&mut self, reader_list: Vec<String>) -> Result<(), Error> {
+        let first_generation = self.writable()?.last_generation + 1;
+        // A file left out of the rewrite may still hold values removed by
+        // tombstones in newer files, so those have to stay
+        let all_files = self.reader_pool.reader_list();
+        let keep_tombstones_in = match all_files
+            .iter()
+            .position(|file_name| !reader_list.contains(file_name))
+        {
+            Some(oldest_left_out) => all_files[oldest_left_out..]
+                .iter()
+                .filter(|file_name| reader_list.contains(file_name))
+                .cloned()
+                .collect(),
+            None => BTreeSet::new(),
+        };
+        let live = LiveRecords {
+            map: Cow::Borrowed(&self.key_dir.map),
+            tombstone_cutoff: self.tombstone_cutoff(),
+            keep_tombstones_in,
+        };
+        let staged = stage_rewrite(
+            &self.path,
+            &self.path,
+            &self.files,
+            self.codec.as_ref(),
+            &reader_list,
+            &live,
+            first_generation..u64::MAX,
+        )?;
+        self.writable()?.last_generation += staged.compacted_files.len() as u64;
+
+        let last_compacted_file = staged.last_compacted_file.clone();
+        self.commit_rewrite(&reader_list, staged)?;
+
+        let (file_name, size) = match last_compacted_file {
+            Some(last_compacted_file) => last_compacted_file,
+            None => {
+                let file_name = self.writable()?.next_log_file_name();
+                self.reader_pool.add_reader(file_name.clone());
+                (file_name, 0)
+            }
+        };
+        self.writable()?.set_active(file_name, size)?;
+
+        Ok(())
+    }
+
+    // Replaces `file_names` with the files staged by `stage_rewrite`.
+    // Renaming the staging directory commits the rewrite, after which the
+    // old files are replaced by the staged ones. A crash before the rename
+    // leaves the old files untouched, a crash after it is finished on the
+    // next open. Keys written again since the rewrite was staged keep their
+    // newer position
+    fn commit_rewrite(
+        &mut self,
+        file_names: &[String],
+        staged: StagedRewrite,
+    ) -> Result<(), Error> {
+        self.rewrites += 1;
+        let kept_files: Vec<String> = self
+            .reader_pool
+            .reader_list()
+            .into_iter()
+            .filter(|file_name| !file_names.contains(file_name))
+            .collect();
+
+        let manifest: Vec<&String> = kept_files
+            .iter()
+            .chain(staged.compacted_files.iter())
+            .collect();
+        commit_staged_files(&self.path, &self.files, &manifest, self.key_dir.last_seq)?;
+
+        for (key, from, to) in staged.positions {
+            self.key_dir.relocate(key, &from, to);
+        }
+        self.key_dir.total_bytes = self
+            .key_dir
+            .total_bytes
+            .saturating_sub(staged.dropped_bytes);
+        self.key_dir.dead_bytes = self.key_dir.dead_bytes.saturating_sub(staged.dropped_bytes);
+
+        self.stats.compaction_records_scanned += staged.records_scanned;
+        self.stats.compaction_records_kept += staged.records_kept;
+        self.stats.compaction_records_dropped += staged.records_dropped;
+        self.stats.compaction_bytes_rewritten += staged.bytes_rewritten;
+
+        self.writable()?.remove_writers(file_names);
+        self.reader_pool.remove_readers(file_names.to_vec());
+        for file_name in staged.compacted_files {
+            self.reader_pool.add_reader(file_name);
+        }
+
+        Ok(())
+    }
+
+    // Tombstones stamped at or before this are past the retention window
+    fn tombstone_cutoff(&self) -> Option<i64> {
+        self.options.tombstone_retention.map(|retention| {
+            self.options.clock_or_default().now().timestamp_millis() - retention.num_milliseconds()
+        })
+    }
+
+    // Only stamped when tombstones are retained, so the log doesn't grow
+    // otherwise
+    fn tombstone_timestamp(&self) -> Option<i64> {
+        self.options
+            .tombstone_retention
+            .map(|_| self.options.clock_or_default().now().timestamp_millis())
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> CommandResult<()> {
+        KvStore::remove(self, key)
+    }
+}
+
+// Engines take `&mut self`, so an engine is shared by putting it behind a
+// lock. These impls let borrowed, boxed and `Arc<Mutex<_>>` shared engines
+// be passed wherever a `KvsEngine` is expected
+impl<E: KvsEngine + ?Sized> KvsEngine for &mut E {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> CommandResult<()> {
+        (**self).remove(key)
+    }
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> CommandResult<()> {
+        (**self).remove(key)
+    }
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Arc<Mutex<E>> {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        lock_engine(self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        lock_engine(self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> CommandResult<()> {
+        lock_engine(self).remove(key)
+    }
+}
+
+// A thread that panicked while holding the lock doesn't make the engine
+// unusable for everyone else. Engine failures are returned as errors, which
+// leave the engine consistent, so the guard is taken over as is
+fn lock_engine<E: ?Sized>(engine: &Mutex<E>) -> std::sync::MutexGuard<'_, E> {
+    engine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Commits a background compaction still running, rather than leave it to
+// be thrown out on the next open. Errors are dropped, the next open cleans
+// up after a failed one
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        let _ = self.finish_background_compaction();
+    }
+}
+
+// Sizes only, never keys or values, which may be secret and are unbounded.
+// Disk usage is left out when the log files can't be listed
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("KvStore");
+        debug
+            .field("path", &self.path)
+            .field("live_keys", &self.key_dir.map.len())
+            .field("log_files", &self.reader_pool.file_names.len());
+        if let Ok(disk_usage) = self.disk_usage() {
+            debug.field("disk_usage", &disk_usage);
+        }
+        debug.field("read_only", &self.writer_pool.is_none());
+        debug.finish_non_exhaustive()
+    }
+}
+
+// Engine without any persistence, all data is lost when it's dropped.
+// Useful as a baseline in benchmarks and for testing code built on `KvsEngine`
+#[derive(Default)]
+pub struct InMemoryKvsEngine {
+    map: HashMap<String, String>,
+}
+
+impl InMemoryKvsEngine {
+    pub fn new() -> InMemoryKvsEngine {
+        InMemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for InMemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> CommandResult<()> {
+        if key.is_empty() {
+            return Err(KvSError::KeyNotProvided.into());
+        }
+
+        self.map.insert(key, value);
+
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> CommandResult<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> CommandResult<()> {
+        if key.is_empty() {
+            return Err(KvSError::KeyNotProvided.into());
+        }
+
+        match self.map.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvSError::KeyNotFound { key }.into()),
+        }
+    }
+}
+
+// Keys are kept sorted so prefix and range operations don't need a full scan
+struct KeyDir {
+    map: BTreeMap<String, LogPosition>,
+    // Highest sequence number in the log
+    last_seq: u64,
+    // Size of every record in the log, and of those superseded by a later
+    // write or a tombstone. Tombstones count as dead when written, whether
+    // or not they're retained, so this only estimates what a compaction
+    // reclaims
+    total_bytes: u64,
+    dead_bytes: u64,
+}
+
+impl KeyDir {
+    fn new() -> KeyDir {
+        KeyDir {
+            map: BTreeMap::new(),
+            last_seq: 0,
+            total_bytes: 0,
+            dead_bytes: 0,
+        }
+    }
+
+    // Replays the log files `threads` at a time. Each file is scanned on
+    // its own, then merged in generation order
+    fn init_with_command_logs(
+        path: impl Into<PathBuf>,
+        files: &LogFiles,
+        codec: &dyn Codec,
+        threads: usize,
+    ) -> Result<KeyDir, Error> {
+        let mut key_dir = KeyDir::new();
+        let log_files = list_log_files(path, files)?;
+        let _span = info_span!("recovery", files = log_files.len()).entered();
+        let started = Instant::now();
+        let mut records = 0;
+
+        for chunk in log_files.chunks(threads.max(1)) {
+            let scans = match chunk {
+                [file_path] => vec![FileScan::read(file_path, codec)],
+                _ => {
+                    // Workers report to the same subscriber and span
+                    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+                    let span = tracing::Span::current();
+                    thread::scope(|scope| {
+                        let handles: Vec<_> = chunk
+                            .iter()
+                            .map(|file_path| {
+                                let (dispatch, span) = (&dispatch, &span);
+                                scope.spawn(move || {
+                                    tracing::dispatcher::with_default(dispatch, || {
+                                        span.in_scope(|| FileScan::read(file_path, codec))
+                                    })
+                                })
+                            })
+                            .collect();
+                        handles
+                            .into_iter()
+                            .map(|handle| handle.join().expect("recovery thread panicked"))
+                            .collect()
+                    })
+                }
+            };
+
+            for scan in scans {
+                let scan = scan?;
+                info!(
+                    file = %scan.file_name,
+                    records = scan.records,
+                    bytes = scan.bytes,
+                    "Recovered log file"
+                );
+                records += scan.records;
+                key_dir.merge(scan);
+            }
+        }
+        info!(
+            records,
+            live_keys = key_dir.map.len(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "Recovery finished"
+        );
+
+        Ok(key_dir)
+    }
+
+    // Applies a scan of the file following every one merged so far. Keys
+    // it touches at all were overwritten or removed by its first record
+    // for them, which `FileScan` leaves for here to count
+    fn merge(&mut self, scan: FileScan) {
+        self.last_seq = self.last_seq.max(scan.last_seq);
+        self.total_bytes += scan.bytes;
+        self.dead_bytes += scan.dead_bytes;
+        for (key, log_position) in scan.keys {
+            let old = match log_position {
+                Some(log_position) => self.map.insert(key, log_position),
+                None => self.map.remove(&key),
+            };
+            if let Some(old) = old {
+                self.dead_bytes += old.size;
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&LogPosition> {
+        self.map.get(key)
+    }
+
+    // Points `key` at a record just written to the log
+    fn set(&mut self, key: String, log_position: LogPosition) {
+        self.total_bytes += log_position.size;
+        if let Some(old) = self.map.insert(key, log_position) {
+            self.dead_bytes += old.size;
+        }
+    }
+
+    // Drops `key` for a tombstone of `tombstone_size` bytes just written to
+    // the log. Tombstones count as dead from the start
+    fn remove(&mut self, key: &str, tombstone_size: u64) {
+        self.total_bytes += tombstone_size;
+        self.dead_bytes += tombstone_size;
+        if let Some(old) = self.map.remove(key) {
+            self.dead_bytes += old.size;
+        }
+    }
+
+    // Points `key` at the copy of its record a compaction made from `from`,
+    // unless the key was written again since
+    fn relocate(&mut self, key: String, from: &LogPosition, to: LogPosition) {
+        if let Some(log_position) = self.map.get_mut(&key) {
+            if log_position.log_file_name == from.log_file_name && log_position.pos == from.pos {
+                *log_position = to;
+            }
+        }
+    }
+
+    // Fraction of the log taken up by records that a compaction would drop
+    fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / self.total_bytes as f64
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.with_prefix(prefix)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a LogPosition)> + 'a {
+        self.map
+            .range(prefix.to_string()..)
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+}
+
+// What one log file does to the KeyDir, read independently of the others:
+// where each key it touches ends up, None when its last record for the key
+// is a tombstone. Byte counts only cover the file itself
+struct FileScan {
+    file_name: String,
+    keys: HashMap<String, Option<LogPosition>>,
+    last_seq: u64,
+    records: u64,
+    bytes: u64,
+    dead_bytes: u64,
+}
+
+impl FileScan {
+    fn read(file_path: &Path, codec: &dyn Codec) -> Result<FileScan, Error> {
+        let file_name = file_path.file_name().unwrap().to_str().unwrap().to_string();
+        let file =
+            File::open(file_path).with_context(|_| format!("Failed to open {}", file_name))?;
+        let mut reader = BufReader::new(file);
+        let mut scan = FileScan {
+            file_name,
+            keys: HashMap::new(),
+            last_seq: 0,
+            records: 0,
+            bytes: 0,
+            dead_bytes: 0,
+        };
+
+        loop {
+            let pos = scan.bytes;
+            let with_position = || {
+                format!(
+                    "Failed to recover record in {} at position {}",
+                    scan.file_name, pos
+                )
+            };
+
+            let (payload, size) =
+                match read_record(&mut reader).with_context(|_| with_position())? {
+                    Some(record) => record,
+                    None => break,
+                };
+            let (seq, command) = codec.decode(&payload).with_context(|_| with_position())?;
+            scan.last_seq = scan.last_seq.max(seq);
+            let old = match command {
+                CommandLog::Set { key, value } => scan.keys.insert(
+                    key,
+                    Some(LogPosition {
+                        pos,
+                        size,
+                        value_len: value.len(),
+                        log_file_name: scan.file_name.clone(),
+                    }),
+                ),
+                CommandLog::Remove { key, .. } => {
+                    // Tombstones count as dead from the start
+                    scan.dead_bytes += size;
+                    scan.keys.insert(key, None)
+                }
+            };
+            if let Some(Some(old)) = old {
+                scan.dead_bytes += old.size;
+            }
+
+            scan.bytes += size;
+            scan.records += 1;
+            if scan.records.is_multiple_of(RECOVERY_PROGRESS_RECORDS) {
+                debug!(file = %scan.file_name, records = scan.records, "Recovery in progress");
+            }
+        }
+
+        Ok(scan)
+    }
+}
+
+// Stateful scan over the keyspace in key order, see `KvStore::cursor`.
+// The cursor borrows the store mutably, so no writes can happen while it's
+// alive and it always sees the keyspace as of its creation
+pub struct Cursor<'a> {
+    store: &'a mut KvStore,
+    next_key: Bound<String>,
+}
+
+impl Cursor<'_> {
+    // Moves the cursor so the next entry is the first key >= `key`
+    pub fn seek(&mut self, key: &str) {
+        self.next_key = Bound::Included(key.to_string());
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = CommandResult<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self
+            .store
+            .key_dir
+            .map
+            .range((self.next_key.clone(), Bound::Unbounded))
+            .next()
+            .map(|(key, _)| key.clone())?;
+        self.next_key = Bound::Excluded(key.clone());
+
+        match self.store.get(key.clone()) {
+            Ok(Some(value)) => Some(Ok(Entry { key, value })),
+            Ok(None) => self.next(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// See `KvStoreOptions::on_evict`
+#[derive(Clone)]
+struct EvictCallback(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for EvictCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EvictCallback")
+    }
+}
+
+// LRU cache of recently read values. A capacity of 0 disables it
+struct ValueCache {
+    capacity: usize,
+    on_evict: Option<EvictCallback>,
+    entries: HashMap<String, (Arc<str>, u64)>,
+    // Last use of each entry, oldest first
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl ValueCache {
+    fn new(capacity: usize, on_evict: Option<EvictCallback>) -> ValueCache {
+        ValueCache {
+            capacity,
+            on_evict,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        self.uses += 1;
+
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.recency.remove(last_used);
+        self.recency.insert(self.uses, key.to_string());
+        *last_used = self.uses;
+
+        Some(Arc::clone(value))
+    }
+
+    fn insert(&mut self, key: String, value: Arc<str>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let (_, least_recently_used) = self.recency.pop_first().unwrap();
+            self.entries.remove(&least_recently_used);
+            if let Some(on_evict) = self.on_evict.as_ref() {
+                (on_evict.0)(&least_recently_used);
+            }
+        }
+
+        self.uses += 1;
+        self.recency.insert(self.uses, key.clone());
+        self.entries.insert(key, (value, self.uses));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+struct WriterPool {
+    path: PathBuf,
+    files: LogFiles,
+    writers: HashMap<String, NamedBufWriter>,
+    curr: String,
+    curr_size: usize,
+    last_generation: u64,
+    background_sync: Option<BackgroundSync>,
+    // Whether the active file's directory entry is known to be on disk
+    curr_dir_synced: bool,
+    // Whether anything was written since the last barrier
+    unsynced_writes: bool,
+    flush_interval: Option<Duration>,
+    max_unflushed_bytes: Option<usize>,
+}
+
+impl WriterPool {
+    // Create hash map with writers to log files, initialized with empty log file
+    fn new(
+        path: impl Into<PathBuf>,
+        files: &LogFiles,
+        initial_generation: u64,
+        flush_interval: Option<Duration>,
+        max_unflushed_bytes: Option<usize>,
+    ) -> Result<WriterPool, Error> {
+        let path = path.into();
+        let latest = latest_log_file_metadata(&path, files).ok();
+        let last_generation = match latest.as_ref() {
+            Some((name, _)) => files.generation(name).unwrap_or(0),
+            None => initial_generation.saturating_sub(1),
+        };
+
+        let mut writer_pool = WriterPool {
+            path,
+            files: files.clone(),
+            writers: HashMap::new(),
+            curr: String::new(),
+            curr_size: 0,
+            last_generation,
+            background_sync: None,
+            curr_dir_synced: false,
+            unsynced_writes: false,
+            flush_interval,
+            max_unflushed_bytes,
+        };
+        match latest {
+            Some((lf_name, lf_size)) if lf_size < COMPACTION_THRESHOLD as u64 => {
+                writer_pool.set_active(lf_name, lf_size as usize)?
+            }
+            Some((lf_name, _)) => {
+                // Sealed here rather than by `roll`, if the store that
+                // filled it died before it could
+                File::open(files.log_file_path(&writer_pool.path, &lf_name))?.sync_all()?;
+                let new_log_file_name = writer_pool.next_log_file_name();
+                writer_pool.set_active(new_log_file_name, 0)?;
+            }
+            None => {
+                let new_log_file_name = writer_pool.next_log_file_name();
+                writer_pool.set_active(new_log_file_name, 0)?;
+            }
+        }
+
+        Ok(writer_pool)
+    }
+
+    // Generations count up from the highest one in the directory, so new
+    // files always sort after existing ones whatever the clock says
+    fn next_log_file_name(&mut self) -> String {
+        self.last_generation += 1;
+        self.files.file_name(self.last_generation)
+    }
+
+    // Makes `file_name` the active log file, creating it if needed, e.g.
+    // the last file written by a compaction
+    fn set_active(&mut self, file_name: String, size: usize) -> Result<(), Error> {
+        let mut writer = NamedBufWriter::new(
+            self.files.log_file_path(&self.path, &file_name),
+            file_name.clone(),
+        )?;
+        writer.flush_interval = self.flush_interval;
+        if let Some(max) = self.max_unflushed_bytes {
+            writer.set_max_unflushed(max)?;
+        }
+        self.writers.insert(file_name.clone(), writer);
+        self.curr = file_name;
+        self.curr_size = size;
+        self.curr_dir_synced = false;
+        self.update_background_sync();
+
+        Ok(())
+    }
+
+    // Starts a new active file, after syncing the one it replaces
+    fn roll(&mut self) -> Result<String, Error> {
+        self.barrier()?;
+        let file_name = self.next_log_file_name();
+        self.set_active(file_name.clone(), 0)?;
+
+        Ok(file_name)
+    }
+
+    fn start_background_sync(&mut self, interval: Duration) {
+        self.background_sync = Some(BackgroundSync::start(interval));
+        self.update_background_sync();
+    }
+
+    fn update_background_sync(&self) {
+        if let Some(background_sync) = self.background_sync.as_ref() {
+            background_sync.set_active(self.files.log_file_path(&self.path, &self.curr));
+        }
+    }
+
+    fn remove_writers(&mut self, file_names: &[String]) {
+        self.writers
+            .retain(|file_name, _| !file_names.contains(file_name));
+    }
+
+    fn active_size(&self) -> usize {
+        self.curr_size
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.writers.get_mut(&self.curr).unwrap().sync()?;
+        Ok(())
+    }
+
+    // Files only ever become inactive through a compaction, which syncs
+    // what it keeps, or `roll`, which syncs them first. So syncing the
+    // active file covers every earlier write
+    fn barrier(&mut self) -> Result<(), Error> {
+        self.writers.get_mut(&self.curr).unwrap().sync_all()?;
+        if !self.curr_dir_synced {
+            sync_dir(
+                self.files
+                    .log_file_path(&self.path, &self.curr)
+                    .parent()
+                    .unwrap(),
+            )?;
+            self.curr_dir_synced = true;
+        }
+        self.unsynced_writes = false;
+
+        Ok(())
+    }
+
+    fn write(&mut self, s: Vec<u8>) -> Result<LogPosition, Error> {
+        let size = FRAMED_RECORD_HEADER_LEN + s.len();
+        let pos = self.writers.get_mut(&self.curr).unwrap().write(s)?;
+        self.curr_size += size;
+        self.unsynced_writes = true;
+        Ok(pos)
+    }
+
+    fn write_all(&mut self, logs: Vec<Vec<u8>>) -> Result<Vec<LogPosition>, Error> {
+        let size = logs
+            .iter()
+            .map(|s| FRAMED_RECORD_HEADER_LEN + s.len())
+            .sum::<usize>();
+        let positions = self.writers.get_mut(&self.curr).unwrap().write_all(logs)?;
+        self.curr_size += size;
+        self.unsynced_writes = true;
+        Ok(positions)
+    }
+}
+
+// Thread syncing the active log file every `interval`. It tracks the file
+// by path, syncing through its own handle. Stops when dropped
+struct BackgroundSync {
+    active_file: Arc<Mutex<Option<PathBuf>>>,
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundSync {
+    fn start(interval: Duration) -> BackgroundSync {
+        let active_file: Arc<Mutex<Option<PathBuf>>> = Arc::new(Mutex::new(None));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread_active_file = Arc::clone(&active_file);
+        let handle = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let file_path = thread_active_file.lock().unwrap().clone();
+                // A failed sync is retried on the next tick, a file removed
+                // by compaction has just been replaced by a synced one
+                if let Some(file) = file_path.and_then(|path| File::open(path).ok()) {
+                    let _ = file.sync_data();
+                }
+            }
+        });
+
+        BackgroundSync {
+            active_file,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    fn set_active(&self, file_path: PathBuf) {
+        *self.active_file.lock().unwrap() = Some(file_path);
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Readers are opened on demand. With `max_open_readers` set, the least
+// recently used reader is closed before opening another one past the cap
+struct ReaderPool {
+    path: PathBuf,
+    files: LogFiles,
+    file_names: BTreeSet<String>,
+    readers: HashMap<String, (File, u64)>,
+    max_open_readers: Option<usize>,
+    uses: u64,
+    // Whether reads may be served from memory maps. Only for read-only
+    // stores, which never write to their files themselves: `reload` starts
+    // a new pool. See `get_map` for the files another writer may change
+    mapped: bool,
+    // `None` for files over `MMAP_MAX_FILE_SIZE`
+    #[cfg(feature = "mmap")]
+    maps: HashMap<String, Option<memmap2::Mmap>>,
+}
+
+impl ReaderPool {
+    fn new(
+        path: impl Into<PathBuf>,
+        files: &LogFiles,
+        max_open_readers: Option<usize>,
+        mapped: bool,
+    ) -> ReaderPool {
+        let path = path.into();
+
+        let file_names = list_log_files(&path, files)
+            .unwrap()
+            .iter()
+            .map(|file_path| file_path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<BTreeSet<_>>();
+        debug!(files = file_names.len(), "Found log files to read");
+
+        ReaderPool {
+            path,
+            files: files.clone(),
+            file_names,
+            readers: HashMap::new(),
+            max_open_readers,
+            uses: 0,
+            mapped,
+            #[cfg(feature = "mmap")]
+            maps: HashMap::new(),
+        }
+    }
+
+    fn add_reader(&mut self, file_name: String) {
+        self.file_names.insert(file_name);
+    }
+
+    fn get_file(&mut self, file_name: &str) -> Result<&File, Error> {
+        self.uses += 1;
+
+        if !self.readers.contains_key(file_name) {
+            if let Some(max_open_readers) = self.max_open_readers {
+                while self.readers.len() >= max_open_readers.max(1) {
+                    let least_recently_used = self
+                        .readers
+                        .iter()
+                        .min_by_key(|(_, (_, last_used))| *last_used)
+                        .map(|(file_name, _)| file_name.clone())
+                        .unwrap();
+                    self.readers.remove(&least_recently_used);
+                }
+            }
+
+            let file = File::open(self.files.log_file_path(&self.path, file_name))?;
+            self.readers
+                .insert(file_name.to_string(), (file, self.uses));
+        }
+
+        let (file, last_used) = self.readers.get_mut(file_name).unwrap();
+        *last_used = self.uses;
+
+        Ok(file)
+    }
+
+    // Oldest file first
+    fn reader_list(&self) -> Vec<String> {
+        let mut file_names: Vec<String> = self.file_names.iter().cloned().collect();
+        file_names.sort_by_cached_key(|file_name| self.files.order(file_name));
+        file_names
+    }
+
+    // Forgets files that were deleted from the store directory
+    fn remove_readers(&mut self, file_names: Vec<String>) {
+        for file_name in file_names {
+            self.file_names.remove(&file_name);
+            self.readers.remove(&file_name);
+            #[cfg(feature = "mmap")]
+            self.maps.remove(&file_name);
+        }
+    }
+
+    fn read_command_log(
+        &mut self,
+        log_position: &LogPosition,
+        codec: &dyn Codec,
+    ) -> Result<(u64, CommandLog), Error> {
+        if self.mapped {
+            if let Some(map) = self.get_map(&log_position.log_file_name)? {
+                return read_command_log_in(map, log_position, codec);
+            }
+        }
+
+        let file = self.get_file(&log_position.log_file_name)?;
+        read_command_log_from(file, log_position, codec)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn get_map(&mut self, file_name: &str) -> Result<Option<&[u8]>, Error> {
+        // The newest file may be the active one of a writer, which cuts it
+        // back when a write fails. Reading a mapping past the end of a
+        // truncated file faults, so it's read through the file instead
+        let newest = self
+            .file_names
+            .iter()
+            .max_by_key(|file_name| self.files.order(file_name));
+        if newest.is_some_and(|newest| newest == file_name) {
+            return Ok(None);
+        }
+
+        if !self.maps.contains_key(file_name) {
+            let file = File::open(self.files.log_file_path(&self.path, file_name))?;
+            let size = file.metadata()?.len();
+            let map = if size > 0 && size <= MMAP_MAX_FILE_SIZE {
+                // Safe as long as nothing truncates the file while it's
+                // mapped. Older files are sealed: no writer appends to or
+                // truncates them again, and compaction deletes them rather
+                // than rewriting them, which leaves the mapping intact
+                Some(unsafe { memmap2::Mmap::map(&file)? })
+            } else {
+                None
+            };
+            self.maps.insert(file_name.to_string(), map);
+        }
+
+        Ok(self.maps[file_name].as_deref())
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn get_map(&mut self, _file_name: &str) -> Result<Option<&[u8]>, Error> {
+        Ok(None)
+    }
+
+    #[cfg(test)]
+    fn read_record_at(&mut self, log_position: &LogPosition) -> Result<Vec<u8>, Error> {
+        let file = self.get_file(&log_position.log_file_name)?;
+        read_record_at(file, log_position)
+    }
+}
+
+// Matches `key` against a glob of `*` and `?` wildcards. On a mismatch it
+// backtracks to the last `*` seen, letting it take one more character
+fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    let mut last_star = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match last_star {
+                Some((star_p, star_k)) => {
+                    last_star = Some((star_p, star_k + 1));
+                    p = star_p + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn read_command_log_from(
+    file: &File,
+    log_position: &LogPosition,
+    codec: &dyn Codec,
+) -> Result<(u64, CommandLog), Error> {
+    let mut reader = BufReader::new(FileReader {
+        file,
+        pos: log_position.pos,
+    });
+    decode_command_log(&mut reader, log_position, codec)
+}
+
+// Same as `read_command_log_from`, for a log file mapped into memory
+fn read_command_log_in(
+    map: &[u8],
+    log_position: &LogPosition,
+    codec: &dyn Codec,
+) -> Result<(u64, CommandLog), Error> {
+    let mut reader = map.get(log_position.pos as usize..).unwrap_or_default();
+    decode_command_log(&mut reader, log_position, codec)
+}
+
+// Reads the record `log_position` points at, returning its sequence number
+// and command
+fn decode_command_log(
+    reader: &mut impl BufRead,
+    log_position: &LogPosition,
+    codec: &dyn Codec,
+) -> Result<(u64, CommandLog), Error> {
+    let with_position = || {
+        format!(
+            "Failed to read record in {} at position {}",
+            log_position.log_file_name, log_position.pos
+        )
+    };
+
+    let payload = read_record_from(reader, log_position).with_context(|_| with_position())?;
+    let sequenced = codec.decode(&payload).with_context(|_| with_position())?;
+
+    Ok(sequenced)
+}
+
+#[cfg(test)]
+fn read_record_at(file: &File, log_position: &LogPosition) -> Result<Vec<u8>, Error> {
+    let mut reader = BufReader::new(FileReader {
+        file,
+        pos: log_position.pos,
+    });
+    read_record_from(&mut reader, log_position)
+}
+
+// Reads the record `log_position` points at, `reader` starting at it
+fn read_record_from(
+    reader: &mut impl BufRead,
+    log_position: &LogPosition,
+) -> Result<Vec<u8>, Error> {
+    let corrupt_log = || -> Error {
+        KvSError::CorruptLog {
+            file_name: log_position.log_file_name.clone(),
+            pos: log_position.pos,
+        }
+        .into()
+    };
+
+    match read_record(reader) {
+        Ok(Some((payload, _))) => Ok(payload),
+        // A stale position, or a record cut short by a truncated file
+        Ok(None) => Err(corrupt_log()),
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof) =>
+        {
+            Err(corrupt_log())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Reads a log file from `pos` onwards. On Unix this uses positional reads,
+// which leave the file's cursor alone, so readers over the same file don't
+// interfere with each other
+struct FileReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Read for FileReader<'_> {
+    #[cfg(unix)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+
+    #[cfg(not(unix))]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = self.file;
+        file.seek(SeekFrom::Start(self.pos))?;
+        let read = file.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+// Reads the record starting at the reader's position, returning its payload
+// and its size in the log. Returns `None` at the end of the log
+fn read_record(reader: &mut impl BufRead) -> Result<Option<(Vec<u8>, u64)>, Error> {
+    let tag = match reader.fill_buf()?.first() {
+        Some(tag) => *tag,
+        None => return Ok(None),
+    };
+
+    match tag {
+        LEGACY_RECORD_TAG => {
+            let mut line = Vec::new();
+            let size = reader.read_until(b'\n', &mut line)?;
+            if line.pop() != Some(b'\n') {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+
+            Ok(Some((line, size as u64)))
+        }
+        FRAMED_RECORD_TAG => {
+            let mut header = [0; FRAMED_RECORD_HEADER_LEN];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload)?;
+
+            Ok(Some((payload, (FRAMED_RECORD_HEADER_LEN + len) as u64)))
+        }
+        tag => Err(failure::format_err!("Unknown record tag {}", tag)),
+    }
+}
+
+fn encode_record(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let len = u32::try_from(payload.len()).map_err(|_| KvSError::RecordTooLarge {
+        size: payload.len(),
+    })?;
+    let mut record = Vec::with_capacity(FRAMED_RECORD_HEADER_LEN + payload.len());
+    record.push(FRAMED_RECORD_TAG);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+// What `NamedBufWriter` needs from the file it appends records to
+trait LogFile: Write + Seek + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn set_len(&self, size: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn try_clone(&self) -> io::Result<File> {
+        File::try_clone(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+struct NamedBufWriter<W: LogFile = File> {
+    writer: BufWriter<W>,
+    file_name: String,
+    // End of the records written so far. Tracked here rather than asked of
+    // the writer, whose position only means something once it's flushed
+    offset: u64,
+    // End of the records known to have reached the file
+    flushed: u64,
+    // Records are flushed as they're written unless set, see
+    // `KvStoreOptions::flush_interval`
+    flush_interval: Option<Duration>,
+    unflushed_since: Option<Instant>,
+    // Also the capacity of the buffer, see
+    // `KvStoreOptions::max_unflushed_bytes`
+    max_unflushed: Option<usize>,
+    // Set when records already acknowledged couldn't be written back after
+    // a failed write, see `roll_back`
+    lost_records: bool,
+}
+
+impl NamedBufWriter {
+    fn new(file_path: PathBuf, file_name: String) -> Result<NamedBufWriter, Error> {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+
+        Ok(NamedBufWriter::with_file(file, file_name, offset))
+    }
+}
+
+impl<W: LogFile> NamedBufWriter<W> {
+    // `offset` is the current length of the file
+    fn with_file(file: W, file_name: String, offset: u64) -> NamedBufWriter<W> {
+        NamedBufWriter {
+            writer: BufWriter::new(file),
+            file_name,
+            offset,
+            flushed: offset,
+            flush_interval: None,
+            unflushed_since: None,
+            max_unflushed: None,
+            lost_records: false,
+        }
+    }
+
+    // Records are flushed as soon as they're written, so a failed write
+    // (e.g. disk full) can be undone before anything else is appended.
+    // Coalesced records written before it are kept
+    fn write(&mut self, s: Vec<u8>) -> Result<LogPosition, Error> {
+        self.writable()?;
+        let start_pos = self.offset;
+
+        let record = encode_record(&s)?;
+        if let Err(e) = self.writer.write_all(&record) {
+            self.roll_back(start_pos, start_pos)?;
+            return Err(e.into());
+        }
+        self.offset += record.len() as u64;
+        if let Err(e) = self.flush_if_due() {
+            self.roll_back(self.offset, start_pos)?;
+            return Err(e.into());
+        }
+
+        Ok(LogPosition {
+            pos: start_pos,
+            size: record.len() as u64,
+            value_len: 0,
+            log_file_name: self.file_name.clone(),
+        })
+    }
+
+    fn write_all(&mut self, logs: Vec<Vec<u8>>) -> Result<Vec<LogPosition>, Error> {
+        self.writable()?;
+        let start_pos = self.offset;
+        let mut pos = start_pos;
+
+        // Encoded up front so an oversized record fails the batch before
+        // any of it is written
+        let records = logs
+            .iter()
+            .map(|s| encode_record(s))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut positions = Vec::with_capacity(records.len());
+        for record in records {
+            if let Err(e) = self.writer.write_all(&record) {
+                self.roll_back(pos, start_pos)?;
+                return Err(e.into());
+            }
+            positions.push(LogPosition {
+                pos,
+                size: record.len() as u64,
+                value_len: 0,
+                log_file_name: self.file_name.clone(),
+            });
+            pos += record.len() as u64;
+        }
+
+        self.offset = pos;
+        if let Err(e) = self.flush_if_due() {
+            self.roll_back(pos, start_pos)?;
+            return Err(e.into());
+        }
+
+        Ok(positions)
+    }
+
+    // With a flush interval, records are only flushed once the oldest
+    // unflushed one is that old, or once there are `max_unflushed` bytes of
+    // them. The buffer flushes itself when it fills up
+    fn flush_if_due(&mut self) -> io::Result<()> {
+        if let Some(interval) = self.flush_interval {
+            let unflushed_since = *self.unflushed_since.get_or_insert_with(Instant::now);
+            let unflushed = (self.offset - self.flushed) as usize;
+            if unflushed_since.elapsed() < interval
+                && self.max_unflushed.is_none_or(|max| unflushed < max)
+            {
+                return Ok(());
+            }
+        }
+
+        self.flush()
+    }
+
+    // Resizes the buffer of a writer nothing was written through yet
+    fn set_max_unflushed(&mut self, max: usize) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        self.writer = BufWriter::with_capacity(max, file);
+        self.max_unflushed = Some(max);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.flushed = self.offset;
+        self.unflushed_since = None;
+        Ok(())
+    }
+
+    // Cuts the log back to `len`, the start of the records that failed to
+    // be written, so no torn record is left behind for the next recovery.
+    // `buffered_end` is where the bytes still in the buffer end. Coalesced
+    // records before `len` were acknowledged, so whatever of them is still
+    // buffered is written out. If that fails too they're lost, and the
+    // writer refuses further writes rather than append where the KeyDir
+    // expects them
+    fn roll_back(&mut self, buffered_end: u64, len: u64) -> Result<(), Error> {
+        let mut file = self.writer.get_ref().try_clone()?;
+        let capacity = self.writer.capacity();
+        let failed_writer = mem::replace(
+            &mut self.writer,
+            BufWriter::with_capacity(capacity, file.try_clone()?),
+        );
+        // Dropping the writer as is would try to flush the buffer again
+        let (_, buffered) = failed_writer.into_parts();
+        let buffered = buffered.unwrap_or_else(|panicked| panicked.into_inner());
+        // Anything in the file past it is part of a failed record
+        let written = buffered_end - buffered.len() as u64;
+
+        file.set_len(written.min(len))?;
+        file.seek(SeekFrom::End(0))?;
+        if written < len {
+            if let Err(e) = file.write_all(&buffered[..(len - written) as usize]) {
+                file.set_len(self.flushed)?;
+                file.seek(SeekFrom::End(0))?;
+                self.lost_records = true;
+                return Err(e.into());
+            }
+        }
+        self.offset = len;
+        self.flushed = len;
+        self.unflushed_since = None;
+
+        Ok(())
+    }
+
+    fn writable(&self) -> Result<(), Error> {
+        if self.lost_records {
+            return Err(failure::format_err!(
+                "Log {} lost records to a failed write, reopen the store",
+                self.file_name
+            ));
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        Ok(())
+    }
+
+    // Flushes and waits for the data to reach the disk
+    fn sync_all(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+// Checks that the directory, if it was used before, was written by
+// `expected_engine`. Directories without a marker pass
+pub fn verify_engine_marker(path: impl AsRef<Path>, expected_engine: &str) -> CommandResult<()> {
+    let marker_path = path.as_ref().join(ENGINE_MARKER_FILE_NAME);
+    if !marker_path.exists() {
+        return Ok(());
+    }
+
+    let marker: EngineMarker = serde_json::from_str(&fs::read_to_string(&marker_path)?)?;
+    if marker.engine != expected_engine {
+        return Err(KvSError::WrongEngine {
+            found: marker.engine,
+            expected: expected_engine.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Reads the marker, if any, and rejects directories of other engines and
+// formats newer than this build
+fn read_engine_marker(path: &Path) -> Result<Option<EngineMarker>, Error> {
+    let marker_path = path.join(ENGINE_MARKER_FILE_NAME);
+    if !marker_path.exists() {
+        return Ok(None);
+    }
+
+    let marker: EngineMarker = serde_json::from_str(&fs::read_to_string(&marker_path)?)?;
+    if marker.engine != ENGINE_NAME {
+        return Err(KvSError::WrongEngine {
+            found: marker.engine,
+            expected: ENGINE_NAME.to_string(),
+        }
+        .into());
+    }
+    if marker.format_version > FORMAT_VERSION {
+        return Err(KvSError::UnsupportedFormat {
+            found: marker.format_version,
+            supported: FORMAT_VERSION,
+        }
+        .into());
+    }
+
+    Ok(Some(marker))
+}
+
+fn check_engine_marker(path: &Path, files: &LogFiles, codec: &dyn Codec) -> Result<(), Error> {
+    let marker_path = path.join(ENGINE_MARKER_FILE_NAME);
+
+    let marker = read_engine_marker(path)?;
+    check_codec(path, files, marker.as_ref(), codec)?;
+    check_layout(marker.as_ref(), files)?;
+    let last_seq = match marker {
+        Some(marker) if marker.format_version == FORMAT_VERSION && marker.layout.is_some() => {
+            return Ok(())
+        }
+        Some(marker) => marker.last_seq,
+        None => 0,
+    };
+
+    // Directories without a marker (or with an older one) are migrated
+    // forward by stamping the current version and layout
+    let marker = EngineMarker {
+        engine: ENGINE_NAME.to_string(),
+        format_version: FORMAT_VERSION,
+        codec: codec.name().to_string(),
+        last_seq,
+        layout: Some(files.layout),
+    };
+    fs::write(marker_path, serde_json::to_string(&marker)?)?;
+
+    Ok(())
+}
+
+// Opening with another layout would miss every file written with the
+// recorded one. Markers that don't record it are trusted to match
+fn check_layout(marker: Option<&EngineMarker>, files: &LogFiles) -> Result<(), Error> {
+    match marker.and_then(|marker| marker.layout) {
+        Some(found) if found != files.layout => Err(KvSError::LayoutMismatch {
+            found: format!("{:?}", found),
+            expected: format!("{:?}", files.layout),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+// Logs in directories without a marker predate it, and were written as JSON
+fn check_codec(
+    path: &Path,
+    files: &LogFiles,
+    marker: Option<&EngineMarker>,
+    codec: &dyn Codec,
+) -> Result<(), Error> {
+    let found = match marker {
+        Some(marker) => marker.codec.clone(),
+        None if !list_log_files(path, files)?.is_empty() => json_codec_name(),
+        None => return Ok(()),
+    };
+
+    if found != codec.name() {
+        return Err(KvSError::CodecMismatch {
+            found,
+            expected: codec.name().to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+// Keeps a second writer from opening the directory until the returned file
+// is closed, retrying for up to `timeout` while another writer holds it.
+// Readers don't take the lock
+fn lock_dir(path: &Path, timeout: Option<Duration>) -> Result<File, Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(LOCK_FILE_NAME))?;
+    let started = Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) => {
+                if started.elapsed() >= timeout.unwrap_or_default() {
+                    return Err(KvSError::Locked.into());
+                }
+                thread::sleep(LOCK_POLL_INTERVAL);
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// Drops an unfinished compaction and completes a committed one
+fn recover_compaction(path: &Path, files: &LogFiles) -> Result<(), Error> {
+    let staging_path = path.join(COMPACTION_STAGING_DIR_NAME);
+    if staging_path.exists() {
+        fs::remove_dir_all(&staging_path)?;
+    }
+
+    if path.join(COMPACTION_COMMITTED_DIR_NAME).exists() {
+        finish_compaction(path, files)?;
+    }
+
+    Ok(())
+}
+
+// Decides which records a rewrite keeps: values the KeyDir points at, and
+// tombstones of keys still removed that are within the retention window or
+// may still hide a value in a file the rewrite leaves alone
+struct LiveRecords<'a> {
+    map: Cow<'a, BTreeMap<String, LogPosition>>,
+    // See `KvStore::tombstone_cutoff`, no tombstone is kept when None
+    tombstone_cutoff: Option<i64>,
+    // Files whose tombstones are kept whatever their age
+    keep_tombstones_in: BTreeSet<String>,
+}
+
+impl LiveRecords<'_> {
+    fn keeps(&self, log: &CommandLog, file_name: &str, pos: u64) -> bool {
+        match log {
+            CommandLog::Set { key, .. } => self
+                .map
+                .get(key)
+                .is_some_and(|log_pos| log_pos.log_file_name == file_name && log_pos.pos == pos),
+            CommandLog::Remove { key, removed_at } => {
+                !self.map.contains_key(key)
+                    && (self.keep_tombstones_in.contains(file_name)
+                        || matches!(
+                            (self.tombstone_cutoff, removed_at),
+                            (Some(cutoff), Some(removed_at)) if *removed_at > cutoff
+                        ))
+            }
+        }
+    }
+}
+
+// Files written by `stage_rewrite`, waiting in the staging directory for
+// `KvStore::commit_rewrite`
+#[derive(Default)]
+struct StagedRewrite {
+    // Oldest first
+    compacted_files: Vec<String>,
+    // Last compacted file and its size, None when nothing was kept
+    last_compacted_file: Option<(String, usize)>,
+    // Where each kept value was, and where its copy is
+    positions: Vec<(String, LogPosition, LogPosition)>,
+    dropped_bytes: u64,
+    records_scanned: u64,
+    records_kept: u64,
+    records_dropped: u64,
+    bytes_rewritten: u64,
+}
+
+// A compaction pass on a thread of its own, see
+// `KvStoreOptions::background_compaction`
+struct BackgroundCompaction {
+    // Files being rewritten, oldest first
+    file_names: Vec<String>,
+    handle: thread::JoinHandle<Result<StagedRewrite, Error>>,
+}
+
+// Copies the records of `file_names` that `live` keeps into the staging
+// directory of the store in `dest`, usually `path` itself, as few files as
+// fit under the compaction threshold, named after `generations` in order.
+// Only reads the given files, so it can run on another thread while writes
+// go to a file not among them
+fn stage_rewrite(
+    path: &Path,
+    dest: &Path,
+    files: &LogFiles,
+    codec: &dyn Codec,
+    file_names: &[String],
+    live: &LiveRecords,
+    mut generations: Range<u64>,
+) -> Result<StagedRewrite, Error> {
+    let staging_path = dest.join(COMPACTION_STAGING_DIR_NAME);
+    if staging_path.exists() {
+        fs::remove_dir_all(&staging_path)?;
+    }
+    fs::create_dir(&staging_path)?;
+
+    let mut staged = StagedRewrite::default();
+    let mut writer: Option<NamedBufWriter> = None;
+
+    for file_name in file_names {
+        let file = File::open(files.log_file_path(path, file_name))?;
+        let mut reader = BufReader::new(file);
+
+        let mut pos = 0;
+        while let Some((payload, size)) = read_record(&mut reader)? {
+            let command_log = codec.decode(&payload)?.1;
+            let from = LogPosition {
+                pos,
+                size,
+                value_len: 0,
+                log_file_name: file_name.clone(),
+            };
+            pos += size;
+
+            staged.records_scanned += 1;
+            if !live.keeps(&command_log, file_name, from.pos) {
+                staged.records_dropped += 1;
+                staged.dropped_bytes += size;
+                continue;
+            }
+
+            // Copied as is, so sequence numbers survive compaction
+            let record_size = FRAMED_RECORD_HEADER_LEN + payload.len();
+
+            let staged_writer = match writer.take() {
+                Some(staged_writer)
+                    if staged_writer.offset as usize + record_size < COMPACTION_THRESHOLD =>
+                {
+                    writer.insert(staged_writer)
+                }
+                full_writer => {
+                    if let Some(mut full_writer) = full_writer {
+                        full_writer.sync_all()?;
+                    }
+                    let generation = generations
+                        .next()
+                        .ok_or_else(|| failure::err_msg("Compaction ran out of generations"))?;
+                    let new_log_file_name = files.file_name(generation);
+                    staged.compacted_files.push(new_log_file_name.clone());
+                    writer.insert(NamedBufWriter::new(
+                        staging_path.join(&new_log_file_name),
+                        new_log_file_name,
+                    )?)
+                }
+            };
+
+            let mut to = staged_writer.write(payload)?;
+            staged.records_kept += 1;
+            staged.bytes_rewritten += record_size as u64;
+            if let CommandLog::Set { key, value } = command_log {
+                to.value_len = value.len();
+                staged.positions.push((key, from, to));
+            }
+        }
+    }
+
+    // Everything staged must be on disk before the rename commits it,
+    // after which the old files get deleted
+    if let Some(mut writer) = writer {
+        writer.sync_all()?;
+        // A file holding an oversized record is full, writes go to a new one
+        if (writer.offset as usize) < COMPACTION_THRESHOLD {
+            staged.last_compacted_file = Some((writer.file_name.clone(), writer.offset as usize));
+        }
+    }
+
+    Ok(staged)
+}
+
+// Commits the files in the staging directory of `path`, after which the
+// store holds exactly the log files listed in `manifest`
+fn commit_staged_files(
+    path: &Path,
+    files: &LogFiles,
+    manifest: &[&String],
+    last_seq: u64,
+) -> Result<(), Error> {
+    let staging_path = path.join(COMPACTION_STAGING_DIR_NAME);
+    let mut manifest_file = File::create(staging_path.join(COMPACTION_MANIFEST_FILE_NAME))?;
+    manifest_file.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+    manifest_file.sync_all()?;
+    // The rewrite may drop the records with the highest sequence numbers,
+    // so the marker remembering them is committed along with it
+    if let Some(mut marker) = read_engine_marker(path)? {
+        marker.last_seq = marker.last_seq.max(last_seq);
+        let mut marker_file = File::create(staging_path.join(ENGINE_MARKER_FILE_NAME))?;
+        marker_file.write_all(serde_json::to_string(&marker)?.as_bytes())?;
+        marker_file.sync_all()?;
+    }
+    sync_dir(&staging_path)?;
+
+    fs::rename(&staging_path, path.join(COMPACTION_COMMITTED_DIR_NAME))?;
+    sync_dir(path)?;
+
+    finish_compaction(path, files)
+}
+
+// Replaces every log file not produced by the committed compaction with the
+// compacted ones. Safe to run again if interrupted
+fn finish_compaction(path: &Path, files: &LogFiles) -> Result<(), Error> {
+    let committed_path = path.join(COMPACTION_COMMITTED_DIR_NAME);
+    let manifest: Vec<String> = serde_json::from_str(&fs::read_to_string(
+        committed_path.join(COMPACTION_MANIFEST_FILE_NAME),
+    )?)?;
+
+    let staged_marker_path = committed_path.join(ENGINE_MARKER_FILE_NAME);
+    if staged_marker_path.exists() {
+        fs::rename(staged_marker_path, path.join(ENGINE_MARKER_FILE_NAME))?;
+    }
+
+    for file_path in list_log_files(path, files)? {
+        let file_name = file_path.file_name().unwrap().to_str().unwrap();
+        if manifest.iter().any(|name| name == file_name) {
+            continue;
+        }
+
+        fs::remove_file(&file_path)?;
+
+        // Drop the bucket directory once its last file is gone
+        if let LogLayout::Nested { .. } = files.layout {
+            let _ = fs::remove_dir(file_path.parent().unwrap());
+        }
+    }
+
+    for file_name in manifest.iter() {
+        let staged_path = committed_path.join(file_name);
+        if staged_path.exists() {
+            let file_path = files.log_file_path(path, file_name);
+            fs::create_dir_all(file_path.parent().unwrap())?;
+            fs::rename(staged_path, file_path)?;
+        }
+    }
+    sync_dir(path)?;
+
+    fs::remove_dir_all(committed_path)?;
+
+    Ok(())
+}
+
+// Copies a directory tree, syncing everything copied
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Error> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let to_path = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to_path)?;
+        } else {
+            fs::copy(entry.path(), &to_path)?;
+            File::open(&to_path)?.sync_all()?;
+        }
+    }
+
+    sync_dir(to)
+}
+
+// Makes renames and new entries in a directory durable. Directories can't
+// be opened as files on every platform, there it's left to the OS
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), Error> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+fn list_log_files(path: impl Into<PathBuf>, files: &LogFiles) -> Result<Vec<PathBuf>, Error> {
+    let path = path.into();
+
+    let mut log_files = match files.layout {
+        LogLayout::Flat => list_log_files_in(&path, files)?,
+        LogLayout::Nested { .. } => {
+            let mut log_files = Vec::new();
+            for entry in fs::read_dir(&path)?.filter_map(|entry| entry.ok()) {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if files.classify(&file_name, entry.path().is_dir()) == StoreEntry::Bucket {
+                    log_files.extend(list_log_files_in(&entry.path(), files)?);
+                }
+            }
+            log_files
+        }
+    };
+
+    // Sort by generation rather than path so nested files keep their order,
+    // and generations of different lengths compare as numbers
+    log_files.sort_by_cached_key(|file_path| {
+        let file_name = file_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        files.order(&file_name)
+    });
+
+    Ok(log_files)
+}
+
+fn list_log_files_in(path: &Path, files: &LogFiles) -> Result<Vec<PathBuf>, Error> {
+    // Read directory entries
+    let entries = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .collect::<Vec<_>>();
+
+    // Keep files named like log files, everything else isn't ours
+    let log_files: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.path().is_file())
+        .filter(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            matches!(files.classify(&file_name, false), StoreEntry::Log { .. })
+        })
+        .map(|entry| entry.path())
+        .collect();
+
+    Ok(log_files)
+}
+
+fn latest_log_file_metadata(
+    path: impl Into<PathBuf>,
+    files: &LogFiles,
+) -> Result<(String, u64), Error> {
+    let log_files = list_log_files(path, files)?;
+    if log_files.is_empty() {
+        return Err(failure::err_msg("No log files found"));
     }
 
     let latest_log_file = log_files.last().unwrap();
@@ -481,3 +3649,408 @@ fn latest_log_file_metadata(path: impl Into<PathBuf>) -> Result<(String, u64), E
         metadata.len(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // In-memory log file that fails every write once `capacity` bytes are
+    // stored, after accepting whatever still fits (a short write)
+    #[derive(Clone)]
+    struct ShortWriteFile {
+        data: Rc<RefCell<Cursor<Vec<u8>>>>,
+        capacity: Rc<RefCell<usize>>,
+    }
+
+    impl Write for ShortWriteFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.data.borrow_mut();
+            let free = self.capacity.borrow().saturating_sub(data.get_ref().len());
+            if free == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            data.write(&buf[..buf.len().min(free)])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for ShortWriteFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.borrow_mut().seek(pos)
+        }
+    }
+
+    impl LogFile for ShortWriteFile {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(self.clone())
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            self.data.borrow_mut().get_mut().truncate(size as usize);
+            Ok(())
+        }
+
+        fn sync_all(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // In-memory log file taking `delay` for every write, like a slow disk
+    #[derive(Clone)]
+    struct SlowFile {
+        data: Rc<RefCell<Cursor<Vec<u8>>>>,
+        delay: Duration,
+    }
+
+    impl Write for SlowFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            self.data.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SlowFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.borrow_mut().seek(pos)
+        }
+    }
+
+    impl LogFile for SlowFile {
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(self.clone())
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            self.data.borrow_mut().get_mut().truncate(size as usize);
+            Ok(())
+        }
+
+        fn sync_all(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn reader_pool_maps_sealed_files_only() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        for generation in 1..=2 {
+            let file_name = format!("kvlog_{}.cmdlog", generation);
+            fs::write(
+                temp_dir.path().join(file_name),
+                encode_record(b"record").unwrap(),
+            )
+            .unwrap();
+        }
+
+        let mut pool = ReaderPool::new(
+            temp_dir.path(),
+            &KvStoreOptions::new().log_files(),
+            None,
+            true,
+        );
+        assert!(pool.get_map("kvlog_1.cmdlog").unwrap().is_some());
+        assert!(pool.get_map("kvlog_2.cmdlog").unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_pool_caps_open_readers() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        for generation in 0..5 {
+            let file_name = format!("kvlog_{}.cmdlog", generation);
+            fs::write(
+                temp_dir.path().join(file_name),
+                encode_record(b"record").unwrap(),
+            )
+            .unwrap();
+        }
+
+        let mut pool = ReaderPool::new(
+            temp_dir.path(),
+            &KvStoreOptions::new().log_files(),
+            Some(2),
+            false,
+        );
+        for _ in 0..2 {
+            for file_name in pool.reader_list() {
+                let position = LogPosition {
+                    pos: 0,
+                    size: encode_record(b"record").unwrap().len() as u64,
+                    value_len: 0,
+                    log_file_name: file_name,
+                };
+                assert_eq!(pool.read_record_at(&position).unwrap(), b"record");
+                assert!(pool.readers.len() <= 2);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_positional_reads() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("kvlog_0.cmdlog");
+        let records: Vec<String> = (0..100).map(|i| format!("record{}", i)).collect();
+        let mut positions = Vec::new();
+        let mut data = Vec::new();
+        for record in records.iter() {
+            positions.push(data.len() as u64);
+            data.extend(encode_record(record.as_bytes()).unwrap());
+        }
+        fs::write(&file_path, data).unwrap();
+
+        let file = File::open(&file_path).unwrap();
+        thread::scope(|scope| {
+            for offset in 0..4 {
+                let (file, records, positions) = (&file, &records, &positions);
+                scope.spawn(move || {
+                    for i in (0..records.len()).rev().skip(offset).step_by(4) {
+                        let mut reader = BufReader::new(FileReader {
+                            file,
+                            pos: positions[i],
+                        });
+                        let (payload, _) = read_record(&mut reader).unwrap().unwrap();
+                        assert_eq!(payload, records[i].as_bytes());
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn positions_read_back_after_reopen() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let file_name = "kvlog_1.cmdlog".to_string();
+        let file_path = temp_dir.path().join(&file_name);
+        let records: Vec<Vec<u8>> = ["ascii", "ünïcödé ✓", "", "last", "reopened"]
+            .iter()
+            .map(|record| record.as_bytes().to_vec())
+            .collect();
+
+        let mut positions = Vec::new();
+        let mut writer = NamedBufWriter::new(file_path.clone(), file_name.clone()).unwrap();
+        positions.extend(writer.write_all(records[..2].to_vec()).unwrap());
+        positions.push(writer.write(records[2].clone()).unwrap());
+        positions.extend(writer.write_all(records[3..4].to_vec()).unwrap());
+        drop(writer);
+        let mut writer = NamedBufWriter::new(file_path, file_name).unwrap();
+        positions.push(writer.write(records[4].clone()).unwrap());
+        drop(writer);
+
+        let mut pool = ReaderPool::new(
+            temp_dir.path(),
+            &KvStoreOptions::new().log_files(),
+            None,
+            false,
+        );
+        for (record, position) in records.iter().zip(positions.iter()) {
+            assert_eq!(&pool.read_record_at(position).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn framing_round_trips_any_bytes() {
+        let payloads: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"\n".to_vec(),
+            b"{\"Set\":{}}\n{".to_vec(),
+            vec![FRAMED_RECORD_TAG, 0, 0, 0, 0],
+            (0..=255).collect(),
+            vec![b'\n'; 10_000],
+        ];
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let file_name = "kvlog_1.cmdlog".to_string();
+        let file_path = temp_dir.path().join(&file_name);
+        let mut writer = NamedBufWriter::new(file_path.clone(), file_name).unwrap();
+        let positions: Vec<LogPosition> = payloads
+            .iter()
+            .map(|payload| writer.write(payload.clone()).unwrap())
+            .collect();
+        drop(writer);
+
+        // Read back in sequence, as recovery does, and by position
+        let mut reader = BufReader::new(File::open(&file_path).unwrap());
+        let mut pool = ReaderPool::new(
+            temp_dir.path(),
+            &KvStoreOptions::new().log_files(),
+            None,
+            false,
+        );
+        for (payload, position) in payloads.iter().zip(positions.iter()) {
+            let (read, size) = read_record(&mut reader).unwrap().unwrap();
+            assert_eq!(&read, payload);
+            assert_eq!(size, encode_record(payload).unwrap().len() as u64);
+            assert_eq!(&pool.read_record_at(position).unwrap(), payload);
+        }
+        assert!(read_record(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn parallel_recovery_matches_sequential() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let options = KvStoreOptions::new().disable_compaction();
+        let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        let value = "v".repeat(1024);
+        for iter in 0..5000 {
+            let key = format!("key{}", iter % 37);
+            if iter % 7 == 0 {
+                let _ = store.remove(key);
+            } else {
+                store.set(key, format!("{}{}", value, iter)).unwrap();
+            }
+        }
+        store.barrier().unwrap();
+        assert!(store.reader_pool.file_names.len() > 4);
+
+        let summary = |key_dir: &KeyDir| {
+            let map: Vec<_> = key_dir
+                .map
+                .iter()
+                .map(|(key, pos)| {
+                    let pos = (&pos.log_file_name, pos.pos, pos.size, pos.value_len);
+                    format!("{} {:?}", key, pos)
+                })
+                .collect();
+            (
+                map,
+                key_dir.total_bytes,
+                key_dir.dead_bytes,
+                key_dir.last_seq,
+            )
+        };
+        let files = KvStoreOptions::new().log_files();
+        let expected = summary(&store.key_dir);
+        for threads in [1, 2, 3, 8] {
+            let key_dir =
+                KeyDir::init_with_command_logs(temp_dir.path(), &files, &JsonCodec, threads)
+                    .unwrap();
+            assert_eq!(summary(&key_dir), expected);
+        }
+    }
+
+    // The fallback `KvStore::move_to` takes across file systems
+    #[test]
+    fn copy_dir_copies_nested_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let from = temp_dir.path().join("from");
+        fs::create_dir_all(from.join("1")).unwrap();
+        fs::write(from.join("engine"), "marker").unwrap();
+        fs::write(from.join("1").join("kvlog_1.cmdlog"), "records").unwrap();
+
+        let to = temp_dir.path().join("to");
+        copy_dir(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(to.join("engine")).unwrap(), "marker");
+        assert_eq!(
+            fs::read_to_string(to.join("1").join("kvlog_1.cmdlog")).unwrap(),
+            "records"
+        );
+        assert!(copy_dir(&from, &to).is_err());
+    }
+
+    #[test]
+    fn unflushed_bytes_stay_bounded() {
+        let file = SlowFile {
+            data: Rc::new(RefCell::new(Cursor::new(Vec::new()))),
+            delay: Duration::from_millis(1),
+        };
+        let mut writer = NamedBufWriter::with_file(file.clone(), "test".to_string(), 0);
+        writer.flush_interval = Some(Duration::from_secs(3600));
+        writer.set_max_unflushed(256).unwrap();
+
+        let payload = vec![b'x'; 40];
+        let record_len = encode_record(&payload).unwrap().len() as u64;
+        for _ in 0..100 {
+            writer.write(payload.clone()).unwrap();
+            assert!(writer.writer.buffer().len() <= 256);
+            assert!(writer.offset - writer.flushed < 256);
+        }
+
+        writer
+            .write_all(vec![payload.clone(), payload.clone(), payload.clone()])
+            .unwrap();
+        assert!(writer.offset - writer.flushed < 256);
+        writer.flush().unwrap();
+        assert_eq!(file.data.borrow().get_ref().len() as u64, 103 * record_len);
+    }
+
+    #[test]
+    fn failed_write_leaves_no_torn_record() {
+        let file = ShortWriteFile {
+            data: Rc::new(RefCell::new(Cursor::new(Vec::new()))),
+            capacity: Rc::new(RefCell::new(16)),
+        };
+        let mut writer = NamedBufWriter::with_file(file.clone(), "test".to_string(), 0);
+        let record1 = encode_record(b"record1").unwrap();
+
+        assert_eq!(writer.write(b"record1".to_vec()).unwrap().pos, 0);
+        assert!(writer.write(b"record2".to_vec()).is_err());
+        assert_eq!(file.data.borrow().get_ref(), &record1);
+
+        assert!(writer
+            .write_all(vec![b"record2".to_vec(), b"record3".to_vec()])
+            .is_err());
+        assert_eq!(file.data.borrow().get_ref(), &record1);
+
+        *file.capacity.borrow_mut() = usize::MAX;
+        assert_eq!(
+            writer.write(b"record2".to_vec()).unwrap().pos,
+            record1.len() as u64
+        );
+        assert_eq!(
+            file.data.borrow().get_ref(),
+            &[record1, encode_record(b"record2").unwrap()].concat()
+        );
+    }
+
+    #[test]
+    fn failed_write_keeps_coalesced_records() {
+        let file = ShortWriteFile {
+            data: Rc::new(RefCell::new(Cursor::new(Vec::new()))),
+            capacity: Rc::new(RefCell::new(16)),
+        };
+        let mut writer = NamedBufWriter::with_file(file.clone(), "test".to_string(), 0);
+        writer.flush_interval = Some(Duration::from_secs(3600));
+        let record1 = encode_record(b"record1").unwrap();
+
+        assert_eq!(writer.write(b"record1".to_vec()).unwrap().pos, 0);
+        assert!(file.data.borrow().get_ref().is_empty());
+        // Bigger than the buffer, so it goes straight to the file
+        assert!(writer.write(vec![b'x'; 16 * 1024]).is_err());
+        assert_eq!(file.data.borrow().get_ref(), &record1);
+
+        *file.capacity.borrow_mut() = usize::MAX;
+        assert_eq!(
+            writer.write(b"record2".to_vec()).unwrap().pos,
+            record1.len() as u64
+        );
+    }
+
+    #[test]
+    fn lost_coalesced_records_stop_writes() {
+        let file = ShortWriteFile {
+            data: Rc::new(RefCell::new(Cursor::new(Vec::new()))),
+            capacity: Rc::new(RefCell::new(4)),
+        };
+        let mut writer = NamedBufWriter::with_file(file.clone(), "test".to_string(), 0);
+        writer.flush_interval = Some(Duration::from_secs(3600));
+
+        writer.write(b"record1".to_vec()).unwrap();
+        assert!(writer.write(vec![b'x'; 16 * 1024]).is_err());
+        assert!(file.data.borrow().get_ref().is_empty());
+
+        *file.capacity.borrow_mut() = usize::MAX;
+        assert!(writer.write(b"record2".to_vec()).is_err());
+        assert!(file.data.borrow().get_ref().is_empty());
+    }
+}
@@ -1,9 +1,17 @@
-use clap::{arg, command, Command};
-use kvs::{CommandResult, KvStore};
+use clap::{arg, command, ArgMatches, Command};
+use kvs::{CommandResult, KvSError, KvStore};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::process;
 
-fn main() -> CommandResult<()> {
-    let mut store = KvStore::open("./")?;
+// Exit codes scripts can rely on. Usage errors are reported by clap, which
+// exits with 2 as well
+const EXIT_KEY_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_OTHER: i32 = 4;
 
+fn main() {
     let matches = command!()
         .version("0.1.0")
         .subcommand_required(true)
@@ -23,35 +31,74 @@ fn main() -> CommandResult<()> {
                 .about("Remove record from key value store")
                 .arg(arg!(<KEY> "Key of the record")),
         )
+        .subcommand(
+            Command::new("export")
+                .about("Writes every record to a file as JSON lines")
+                .arg(arg!(<FILE> "File to write to, - for stdout")),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Inserts the records of a file written by export")
+                .arg(arg!(<FILE> "File to read from, - for stdin")),
+        )
         .get_matches();
 
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        process::exit(exit_code(&e))
+    }
+}
+
+fn run(matches: &ArgMatches) -> CommandResult<()> {
+    let mut store = KvStore::open("./")?;
+
     match matches.subcommand() {
         Some(("set", sub_matches)) => store.set(
             sub_matches.get_one::<String>("KEY").unwrap().to_string(),
             sub_matches.get_one::<String>("VALUE").unwrap().to_string(),
         ),
         Some(("get", sub_matches)) => {
-            let value = store.get(sub_matches.get_one::<String>("KEY").unwrap().to_string());
-            match value {
-                Ok(Some(value)) => println!("{}", value),
-                Ok(None) => println!("Key not found"),
-                Err(e) => println!("{}", e),
+            match store.get(sub_matches.get_one::<String>("KEY").unwrap().to_string())? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
             }
 
             Ok(())
         }
         Some(("rm", sub_matches)) => {
-            let res = store.remove(sub_matches.get_one::<String>("KEY").unwrap().to_string());
-            match res {
-                Err(e) => {
-                    println!("{}", e);
-                    std::process::exit(1)
-                }
-                _ => (),
+            store.remove(sub_matches.get_one::<String>("KEY").unwrap().to_string())
+        }
+        Some(("export", sub_matches)) => {
+            match sub_matches.get_one::<String>("FILE").unwrap().as_str() {
+                "-" => store.export(io::stdout().lock()),
+                file => store.export(BufWriter::new(File::create(file)?)),
             }
+        }
+        Some(("import", sub_matches)) => {
+            match sub_matches.get_one::<String>("FILE").unwrap().as_str() {
+                "-" => store.import(io::stdin().lock())?,
+                file => store.import(BufReader::new(File::open(file)?))?,
+            };
 
             Ok(())
         }
         _ => unreachable!("Provide a command"),
     }
 }
+
+fn exit_code(e: &failure::Error) -> i32 {
+    match e
+        .iter_chain()
+        .find_map(|cause| cause.downcast_ref::<KvSError>())
+    {
+        Some(KvSError::KeyNotFound { .. }) => EXIT_KEY_NOT_FOUND,
+        Some(KvSError::KeyNotProvided) => EXIT_USAGE,
+        _ if e
+            .iter_chain()
+            .any(|cause| cause.downcast_ref::<io::Error>().is_some()) =>
+        {
+            EXIT_IO
+        }
+        _ => EXIT_OTHER,
+    }
+}
@@ -1,18 +1,37 @@
+// The CLI tests pass their arguments by reference, as they always have.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
+#[cfg(feature = "cli")]
 use assert_cmd::prelude::*;
-use kvs::{CommandResult, KvStore};
+use chrono::{Duration, TimeZone, Utc};
+use kvs::{
+    BincodeCodec, Clock, Codec, CommandLog, CommandResult, Entry, InMemoryKvsEngine, JsonCodec,
+    KvSError, KvStore, KvStoreOptions, KvsEngine, LogLayout, ManualClock, Snapshot, Stats,
+    ValueRef,
+};
+#[cfg(feature = "cli")]
 use predicates::ord::eq;
+#[cfg(feature = "cli")]
+use predicates::prelude::PredicateBooleanExt;
+#[cfg(feature = "cli")]
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
+#[cfg(feature = "cli")]
 use std::process::Command;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // `kvs` with no args should exit with a non-zero code.
+#[cfg(feature = "cli")]
 #[test]
 fn cli_no_args() {
     Command::cargo_bin("kvs").unwrap().assert().failure();
 }
 
 // `kvs -V` should print the version
+#[cfg(feature = "cli")]
 #[test]
 fn cli_version() {
     Command::cargo_bin("kvs")
@@ -23,6 +42,7 @@ fn cli_version() {
 }
 
 // `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
+#[cfg(feature = "cli")]
 #[test]
 fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
@@ -36,6 +56,7 @@ fn cli_get_non_existent_key() {
 }
 
 // `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+#[cfg(feature = "cli")]
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -44,11 +65,13 @@ fn cli_rm_non_existent_key() {
         .args(&["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(1)
+        .stdout(is_empty())
+        .stderr(eq("Key not found").trim());
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
+#[cfg(feature = "cli")]
 #[test]
 fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -61,6 +84,7 @@ fn cli_set() {
         .stdout(is_empty());
 }
 
+#[cfg(feature = "cli")]
 #[test]
 fn cli_get_stored() -> CommandResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -90,6 +114,7 @@ fn cli_get_stored() -> CommandResult<()> {
 }
 
 // `kvs rm <KEY>` should print nothing and exit with zero.
+#[cfg(feature = "cli")]
 #[test]
 fn cli_rm_stored() -> CommandResult<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -117,6 +142,7 @@ fn cli_rm_stored() -> CommandResult<()> {
     Ok(())
 }
 
+#[cfg(feature = "cli")]
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -132,6 +158,7 @@ fn cli_invalid_get() {
         .failure();
 }
 
+#[cfg(feature = "cli")]
 #[test]
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
@@ -153,6 +180,7 @@ fn cli_invalid_set() {
         .failure();
 }
 
+#[cfg(feature = "cli")]
 #[test]
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
@@ -168,6 +196,7 @@ fn cli_invalid_rm() {
         .failure();
 }
 
+#[cfg(feature = "cli")]
 #[test]
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
@@ -177,6 +206,65 @@ fn cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs export` followed by `kvs import` into an empty directory should
+// reproduce every record, through files as well as stdout and stdin.
+#[cfg(feature = "cli")]
+#[test]
+fn cli_export_import() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "multi\nline \"välue\"".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    let mut expected = Vec::new();
+    store.export(&mut expected)?;
+    drop(store);
+
+    let export_path = temp_dir.path().join("export.jsonl");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", export_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "-"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+
+    let file_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", export_path.to_str().unwrap()])
+        .current_dir(&file_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let stdin_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "-"])
+        .current_dir(&stdin_dir)
+        .with_stdin()
+        .buffer(expected.clone())
+        .assert()
+        .success();
+
+    for dir in [&file_dir, &stdin_dir] {
+        let mut imported = Vec::new();
+        KvStore::open(dir.path())?.export(&mut imported)?;
+        assert_eq!(imported, expected);
+    }
+    assert_eq!(String::from_utf8(expected)?.lines().count(), 2);
+
+    Ok(())
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> CommandResult<()> {
@@ -298,3 +386,2512 @@ fn compaction() -> CommandResult<()> {
 
     panic!("No compaction detected");
 }
+
+// Opening a directory stamped by a newer format version should fail cleanly.
+#[test]
+fn open_unsupported_format() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::write(
+        temp_dir.path().join("engine"),
+        r#"{"engine":"kvs","format_version":999}"#,
+    )?;
+
+    let err = KvStore::open(temp_dir.path()).expect_err("open should fail");
+    match err.downcast_ref::<KvSError>() {
+        Some(KvSError::UnsupportedFormat { found, .. }) => assert_eq!(*found, 999),
+        _ => panic!("unexpected error: {}", err),
+    }
+
+    Ok(())
+}
+
+fn check_engine_contract(engine: &mut impl KvsEngine) -> CommandResult<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    match engine
+        .remove("key1".to_owned())
+        .unwrap_err()
+        .downcast::<KvSError>()
+    {
+        Ok(KvSError::KeyNotFound { key }) => assert_eq!(key, "key1"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(engine.set("".to_owned(), "value".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Both engines should behave the same through the `KvsEngine` trait.
+#[test]
+fn engine_contract() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_engine_contract(&mut KvStore::open(temp_dir.path())?)?;
+    check_engine_contract(&mut InMemoryKvsEngine::new())?;
+
+    Ok(())
+}
+
+// `set_all` should persist every record.
+#[test]
+fn set_all() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set_all((0..100).map(|i| (format!("key{}", i), format!("value{}", i))))?;
+    assert!(store
+        .set_all(vec![("".to_owned(), "value".to_owned())])
+        .is_err());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// The compaction counter should track compactions triggered by writes.
+#[test]
+fn compaction_counter() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.compactions(), 0);
+    assert!(store.last_compaction_at().is_none());
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+    }
+
+    assert!(store.compactions() >= 1);
+    assert!(store.last_compaction_at().is_some());
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// `delete_prefix` should only remove keys under the given prefix.
+#[test]
+fn delete_prefix() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..5 {
+        store.set(format!("user:1:{}", i), "value".to_owned())?;
+        store.set(format!("user:2:{}", i), "value".to_owned())?;
+    }
+    store.set("user:10".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.delete_prefix("user:1:")?, 5);
+    assert_eq!(store.delete_prefix("user:1:")?, 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("user:1:{}", i))?, None);
+        assert_eq!(
+            store.get(format!("user:2:{}", i))?,
+            Some("value".to_owned())
+        );
+    }
+    assert_eq!(store.get("user:10".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// `retain` should remove exactly the entries failing the predicate, and
+// the removals should survive a reopen.
+#[test]
+fn retain() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        let state = if iter % 3 == 0 { "expired" } else { "active" };
+        store.set(format!("session{}", iter), state.to_owned())?;
+    }
+
+    assert_eq!(store.retain(|_, value| value != "expired")?, 4);
+    assert_eq!(store.retain(|_, value| value != "expired")?, 0);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        let expected = if iter % 3 == 0 {
+            None
+        } else {
+            Some("active".to_owned())
+        };
+        assert_eq!(store.get(format!("session{}", iter))?, expected);
+    }
+
+    Ok(())
+}
+
+// `value_len` should agree with the values `get` returns, through
+// overwrites, a reopen and a compaction.
+#[test]
+fn value_len() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_all(vec![
+        ("key2".to_owned(), String::new()),
+        ("key3".to_owned(), "ü".repeat(10)),
+    ])?;
+    store.set("key1".to_owned(), "v".repeat(100))?;
+    store.remove("key2".to_owned())?;
+
+    let check = |store: &mut KvStore| -> CommandResult<()> {
+        for key in ["key1", "key2", "key3", "missing"] {
+            let len = store.get(key.to_owned())?.map(|value| value.len());
+            assert_eq!(store.value_len(key), len);
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    assert_eq!(store.value_len("key3"), Some(20));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    while store.compactions() == 0 {
+        store.set("key4".to_owned(), "v".repeat(1024))?;
+    }
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// `count_prefix` should agree with counting the matching entries.
+#[test]
+fn count_prefix() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..20 {
+        store.set(format!("user:{}", iter), format!("value{}", iter))?;
+        store.set(format!("order:{}", iter), format!("value{}", iter))?;
+    }
+    store.remove("user:3".to_owned())?;
+    store.set("user".to_owned(), "value".to_owned())?;
+
+    for prefix in ["user:", "user:1", "order:", "user", "", "missing"] {
+        let expected = store.filter(|key, _| key.starts_with(prefix))?.len();
+        assert_eq!(store.count_prefix(prefix), expected);
+    }
+    assert_eq!(store.count_prefix("user:"), 19);
+
+    Ok(())
+}
+
+// The nested layout should keep log files in subdirectories and recover
+// from them, also across compactions.
+#[test]
+fn nested_layout() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().layout(LogLayout::Nested { bucket_size: 1 });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    assert!(store.compactions() >= 1);
+
+    let top_level_logs = fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "cmdlog"))
+        .count();
+    assert_eq!(top_level_logs, 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, 1990 + key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Files and directories that aren't the store's own should be ignored by
+// recovery and survive compaction untouched.
+#[test]
+fn stray_files_ignored() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stray_files = [
+        "README",
+        ".DS_Store",
+        "snapshot.cmdlog",
+        "kvlog_snapshot.cmdlog",
+        "kvlog_1.cmdlog.bak",
+    ];
+    for file_name in stray_files {
+        fs::write(temp_dir.path().join(file_name), "not a log")?;
+    }
+    fs::create_dir(temp_dir.path().join("backup"))?;
+    fs::write(
+        temp_dir.path().join("backup").join("kvlog_1.cmdlog"),
+        "not a log",
+    )?;
+
+    let value = "v".repeat(1024);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    assert!(store.compactions() >= 1);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, 1990 + key_id))
+        );
+    }
+    for file_name in stray_files {
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(file_name))?,
+            "not a log"
+        );
+    }
+    assert!(temp_dir
+        .path()
+        .join("backup")
+        .join("kvlog_1.cmdlog")
+        .exists());
+
+    // The nested layout only looks into numbered buckets.
+    let nested_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().layout(LogLayout::Nested { bucket_size: 1 });
+    fs::create_dir(nested_dir.path().join("backup"))?;
+    fs::write(
+        nested_dir.path().join("backup").join("kvlog_1.cmdlog"),
+        "not a log",
+    )?;
+    let mut store = KvStore::open_with_options(nested_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_options(nested_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A value larger than the compaction threshold should be stored in a log
+// file of its own, without compacting the store on every following write.
+#[test]
+fn oversized_value() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let large_value = "l".repeat(2 * 1024 * 1024);
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), large_value.clone())?;
+    store.set("after".to_owned(), "value".to_owned())?;
+    assert_eq!(store.compactions(), 0);
+    assert_eq!(log_files(temp_dir.path()).len(), 3);
+    assert_eq!(store.get("large".to_owned())?, Some(large_value.clone()));
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    assert!(store.compactions() >= 1);
+    assert!(store.compactions() < 5);
+    assert_eq!(store.get("large".to_owned())?, Some(large_value.clone()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large_value));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, 1990 + key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// `reload` should pick up keys written by another store on the same directory.
+#[test]
+fn reload() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    writer.set("key1".to_owned(), "value2".to_owned())?;
+    writer.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    reader.reload()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Read-only stores, memory-mapped with the `mmap` feature, should return the
+// same values as a writable store reading through its files.
+#[test]
+fn read_only_reads_match() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for iter in 0..1500 {
+        writer.set(format!("key{}", iter % 100), format!("{}{}", value, iter))?;
+    }
+    for key_id in 0..10 {
+        writer.remove(format!("key{}", key_id))?;
+    }
+    writer.set("empty".to_owned(), String::new())?;
+    writer.barrier()?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    let keys: Vec<_> = (0..100)
+        .map(|key_id| format!("key{}", key_id))
+        .chain(["empty".to_owned(), "missing".to_owned()])
+        .collect();
+    for key in keys.iter() {
+        assert_eq!(reader.get(key.clone())?, writer.get(key.clone())?);
+    }
+
+    // Files grown or added since are picked up by `reload`.
+    for iter in 0..200 {
+        writer.set(format!("key{}", iter % 20), format!("new{}", iter))?;
+    }
+    writer.barrier()?;
+    reader.reload()?;
+    for key in keys.iter() {
+        assert_eq!(reader.get(key.clone())?, writer.get(key.clone())?);
+    }
+
+    Ok(())
+}
+
+// `compact_into` should write a copy holding the same entries in less space,
+// leaving the source's files alone.
+#[test]
+fn compact_into() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().codec(Arc::new(BincodeCodec));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..1500 {
+        store.set(format!("key{}", iter % 100), format!("{}{}", value, iter))?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let source_files: Vec<_> = log_files(temp_dir.path())
+        .into_iter()
+        .map(|file_path| fs::read(file_path).unwrap())
+        .collect();
+
+    store.compact_into(copy_dir.path())?;
+    assert_eq!(
+        log_files(temp_dir.path())
+            .into_iter()
+            .map(|file_path| fs::read(file_path).unwrap())
+            .collect::<Vec<_>>(),
+        source_files
+    );
+
+    let mut copy = KvStore::open_with_options(copy_dir.path(), options)?;
+    assert!(copy.disk_usage()? < store.disk_usage()?);
+    let mut exported = Vec::new();
+    store.export(&mut exported)?;
+    let mut copy_exported = Vec::new();
+    copy.export(&mut copy_exported)?;
+    assert_eq!(copy_exported, exported);
+
+    // A directory already holding a store is refused.
+    drop(copy);
+    assert!(store.compact_into(copy_dir.path()).is_err());
+
+    Ok(())
+}
+
+// `get_with_metadata` should point at the record holding the value, and
+// number later writes higher.
+#[test]
+fn get_with_metadata() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let key1 = store.get_with_metadata("key1".to_owned())?.unwrap();
+    let key2 = store.get_with_metadata("key2".to_owned())?.unwrap();
+    assert_eq!(key1.value, "value3");
+    assert_eq!(key2.value, "value2");
+    assert!(key1.seq > key2.seq);
+    assert!(key2.seq > 0);
+
+    let log = fs::read(temp_dir.path().join(&key1.log_file_name))?;
+    let record = &log[key1.pos as usize..(key1.pos + key1.size) as usize];
+    let record = String::from_utf8_lossy(record);
+    assert!(record.contains("\"key1\""));
+    assert!(record.contains("\"value3\""));
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get_with_metadata("key2".to_owned())?, None);
+    assert_eq!(store.get_with_metadata("missing".to_owned())?, None);
+
+    Ok(())
+}
+
+// `keys_matching` should support wildcards anywhere in the pattern.
+#[test]
+fn keys_matching() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in [
+        "user:1:name",
+        "user:1:email",
+        "user:22:name",
+        "user:name",
+        "group:1:name",
+        "usér:3:name",
+    ] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.set("user:3:name".to_owned(), "value".to_owned())?;
+    store.remove("user:3:name".to_owned())?;
+
+    assert_eq!(
+        store.keys_matching("user:*:name"),
+        vec!["user:1:name".to_owned(), "user:22:name".to_owned()]
+    );
+    assert_eq!(
+        store.keys_matching("*:?:name"),
+        vec![
+            "group:1:name".to_owned(),
+            "user:1:name".to_owned(),
+            "usér:3:name".to_owned()
+        ]
+    );
+    assert_eq!(
+        store.keys_matching("us?r*name"),
+        vec![
+            "user:1:name".to_owned(),
+            "user:22:name".to_owned(),
+            "user:name".to_owned(),
+            "usér:3:name".to_owned()
+        ]
+    );
+    assert_eq!(
+        store.keys_matching("user:1:*"),
+        vec!["user:1:email".to_owned(), "user:1:name".to_owned()]
+    );
+    assert_eq!(
+        store.keys_matching("user:1:name"),
+        vec!["user:1:name".to_owned()]
+    );
+    assert_eq!(store.keys_matching("*:*:*:*"), Vec::<String>::new());
+    assert_eq!(store.keys_matching("*").len(), 6);
+
+    Ok(())
+}
+
+// The store's debug output should give its path and sizes, not its data.
+#[test]
+fn debug_output() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "secret-value".to_owned())?;
+    }
+    store.barrier()?;
+
+    let debug = format!("{:?}", store);
+    assert!(debug.contains(&format!("{:?}", temp_dir.path())));
+    assert!(debug.contains("live_keys: 3"));
+    assert!(debug.contains("log_files: 1"));
+    assert!(debug.contains(&format!("disk_usage: {}", store.disk_usage()?)));
+    assert!(!debug.contains("secret-value"));
+    assert!(!debug.contains("key0"));
+
+    Ok(())
+}
+
+// With compaction disabled, every version of every key should stay in the
+// log, across many rolled over files.
+#[test]
+fn disable_compaction() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .disable_compaction()
+        .max_generations(2);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.compactions(), 0);
+    let files = log_files(temp_dir.path());
+    assert!(files.len() > 2);
+
+    // Open from disk again, nothing gets compacted on open either.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(log_files(temp_dir.path()), files);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("{}{}", value, 1991))
+    );
+
+    let mut dump = Vec::new();
+    store.debug_dump(&mut dump)?;
+    let dump = String::from_utf8(dump)?;
+    assert_eq!(dump.lines().count(), 2001);
+    assert_eq!(
+        dump.lines().filter(|line| line.contains(" live ")).count(),
+        9
+    );
+    assert!(dump.lines().last().unwrap().contains("Remove"));
+
+    Ok(())
+}
+
+// `move_to` should reopen the store at its new path with all its keys,
+// leaving nothing at the old one.
+#[test]
+fn move_to() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_path = temp_dir.path().join("store");
+    let moved_path = temp_dir.path().join("moved");
+    let options = KvStoreOptions::new().layout(LogLayout::Nested { bucket_size: 1 });
+    let mut store = KvStore::open_with_options(&store_path, options)?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..1500 {
+        store.set(format!("key{}", iter % 100), format!("{}{}", value, iter))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut store = store.move_to(&moved_path)?;
+    assert!(!store_path.exists());
+    assert_eq!(store.path(), moved_path);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, 1400 + key_id))
+        );
+    }
+    // The moved store still holds the lock.
+    assert!(KvStore::open(&moved_path).is_err());
+
+    // The destination must not exist yet.
+    let other = KvStore::open(temp_dir.path().join("other"))?;
+    assert!(other.move_to(&moved_path).is_err());
+
+    Ok(())
+}
+
+// The eviction callback should get the least recently used keys as the
+// value cache makes room, and nothing for values dropped by writes.
+#[test]
+fn on_evict() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let callback_evicted = Arc::clone(&evicted);
+    let options = KvStoreOptions::new()
+        .value_cache_capacity(2)
+        .on_evict(move |key| callback_evicted.lock().unwrap().push(key.to_owned()));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 1..=4 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    assert!(evicted.lock().unwrap().is_empty());
+
+    store.get("key3".to_owned())?;
+    store.get("key4".to_owned())?;
+    assert_eq!(*evicted.lock().unwrap(), vec!["key2", "key1"]);
+
+    store.set("key3".to_owned(), "value5".to_owned())?;
+    store.remove("key4".to_owned())?;
+    assert_eq!(evicted.lock().unwrap().len(), 2);
+
+    Ok(())
+}
+
+fn log_files(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut log_files: Vec<_> = fs::read_dir(path)
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "cmdlog"))
+        .collect();
+    log_files.sort();
+    log_files
+}
+
+// Writes a log file of the given generation into `path`, as if left behind
+// by a crash, using a scratch store for `write`. Returns its file name.
+fn write_generation(
+    path: &std::path::Path,
+    generation: u64,
+    write: impl FnOnce(&mut KvStore) -> CommandResult<()>,
+) -> CommandResult<String> {
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().initial_generation(generation);
+    let mut store = KvStore::open_with_options(other_dir.path(), options)?;
+    write(&mut store)?;
+    drop(store);
+    let file_name = format!("kvlog_{}.cmdlog", generation);
+    fs::copy(other_dir.path().join(&file_name), path.join(&file_name))?;
+    Ok(file_name)
+}
+
+// Errors from the read path and recovery should name the key and log file.
+#[test]
+fn error_context() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log_file = log_files(temp_dir.path()).pop().unwrap();
+    let file_name = log_file.file_name().unwrap().to_str().unwrap().to_owned();
+    let corrupted = fs::read_to_string(&log_file)?.replacen('{', "#", 1);
+    fs::write(&log_file, corrupted)?;
+
+    let err = store.get("key1".to_owned()).unwrap_err();
+    let chain: Vec<String> = err.iter_chain().map(|cause| cause.to_string()).collect();
+    assert!(chain.iter().any(|cause| cause.contains("key1")));
+    assert!(chain.iter().any(|cause| cause.contains(&file_name)));
+
+    drop(store);
+    let err = KvStore::open(temp_dir.path()).expect_err("open should fail");
+    assert!(err.to_string().contains(&file_name));
+
+    Ok(())
+}
+
+// Reads should keep working across many log files with a low open reader cap.
+#[test]
+fn max_open_readers() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for generation in 0..10 {
+        fs::write(
+            temp_dir.path().join(format!("kvlog_{}.cmdlog", generation)),
+            format!(
+                "{{\"Set\":{{\"key\":\"key{0}\",\"value\":\"value{0}\"}}}}\n",
+                generation
+            ),
+        )?;
+    }
+
+    let options = KvStoreOptions::new().max_open_readers(2);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for _ in 0..2 {
+        for generation in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", generation))?,
+                Some(format!("value{}", generation))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Warm gets should count as cache hits and cold gets as misses.
+#[test]
+fn cache_stats() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_cache_capacity(2);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.stats().cache_hits, 1);
+    assert_eq!(store.stats().cache_misses, 1);
+
+    // key1 and key2 push key0 out of the cache.
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key0".to_owned())?;
+    assert_eq!(store.stats().cache_hits, 1);
+    assert_eq!(store.stats().cache_misses, 4);
+
+    // Overwrites must not be served from a stale cache entry.
+    store.set("key0".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats().cache_misses, 5);
+
+    Ok(())
+}
+
+// Logs holding both newline delimited JSON records (format 1) and framed
+// records should be readable, also after reopening.
+#[test]
+fn mixed_format_log() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvlog_1.cmdlog"),
+        concat!(
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+            "{\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n",
+        ),
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// `set_if_absent` should only write keys that don't exist yet.
+#[test]
+fn set_if_absent() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Exactly one of two racing callers wins.
+    let store = Arc::new(Mutex::new(store));
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                store
+                    .lock()
+                    .unwrap()
+                    .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let winners = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|inserted| *inserted)
+        .count();
+    assert_eq!(winners, 1);
+
+    Ok(())
+}
+
+// A crash in the middle of a compaction should recover to either the state
+// before it or after it.
+#[test]
+fn interrupted_compaction() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let old_log_file = log_files(temp_dir.path()).pop().unwrap();
+
+    // Crash while compacted files are still being staged.
+    let staging_dir = temp_dir.path().join(".compacting");
+    fs::create_dir(&staging_dir)?;
+    fs::write(
+        staging_dir.join("kvlog_99999999999999999999.cmdlog"),
+        "garbage",
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!staging_dir.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Crash after committing, before the old files were deleted.
+    let committed_dir = temp_dir.path().join(".compacted");
+    fs::create_dir(&committed_dir)?;
+    fs::copy(
+        &old_log_file,
+        committed_dir.join("kvlog_9999999999999999999.cmdlog"),
+    )?;
+    fs::write(
+        committed_dir.join("manifest"),
+        r#"["kvlog_9999999999999999999.cmdlog"]"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!committed_dir.exists());
+    assert_eq!(
+        log_files(temp_dir.path()),
+        vec![temp_dir.path().join("kvlog_9999999999999999999.cmdlog")]
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A cursor should return keys in order from wherever it's seeked to.
+#[test]
+fn cursor() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let entries = store.cursor().collect::<CommandResult<Vec<_>>>()?;
+    assert_eq!(entries.len(), 10);
+    assert_eq!(
+        entries[0],
+        Entry {
+            key: "key0".to_owned(),
+            value: "value0".to_owned()
+        }
+    );
+
+    let mut cursor = store.cursor();
+    cursor.seek("key45");
+    let keys = cursor
+        .map(|entry| entry.map(|entry| entry.key))
+        .collect::<CommandResult<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key5", "key6", "key7", "key8", "key9"]);
+
+    Ok(())
+}
+
+// Reading a record cut short by the end of the file should report a corrupt
+// log instead of returning a wrong value.
+#[test]
+fn read_past_end_of_log() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Cut the file in the middle of the key2 record.
+    let log_file = log_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&log_file)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len * 2 / 3 - 5)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for key in ["key2", "key3"] {
+        let err = store.get(key.to_owned()).unwrap_err();
+        let corrupt_log = err
+            .iter_chain()
+            .find_map(|cause| cause.downcast_ref::<KvSError>());
+        assert!(
+            matches!(corrupt_log, Some(KvSError::CorruptLog { .. })),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction timestamps should follow an injected clock.
+#[test]
+fn manual_clock() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start = Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+    let clock = Arc::new(ManualClock::new(start));
+    let options = KvStoreOptions::new().clock(clock.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+    }
+    assert!(store.compactions() >= 1);
+    assert_eq!(store.last_compaction_at(), Some(start));
+
+    clock.advance(Duration::minutes(5));
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+    }
+    assert_eq!(store.last_compaction_at(), Some(clock.now()));
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// `filter` should return only the entries matching the predicate.
+#[test]
+fn filter() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("a".to_owned(), "red apple".to_owned())?;
+    store.set("b".to_owned(), "banana".to_owned())?;
+    store.set("c".to_owned(), "red cherry".to_owned())?;
+    store.set("d".to_owned(), "red".to_owned())?;
+    store.remove("d".to_owned())?;
+
+    assert_eq!(
+        store.filter(|_, value| value.contains("red"))?,
+        vec![
+            Entry {
+                key: "a".to_owned(),
+                value: "red apple".to_owned()
+            },
+            Entry {
+                key: "c".to_owned(),
+                value: "red cherry".to_owned()
+            },
+        ]
+    );
+    assert!(store.filter(|key, _| key == "z")?.is_empty());
+
+    Ok(())
+}
+
+// A read-only store should serve reads without touching its directory.
+#[test]
+fn open_read_only() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let listing = || -> Vec<(std::path::PathBuf, u64)> {
+        let mut entries: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.path().to_owned(), entry.metadata().unwrap().len()))
+            .collect();
+        entries.sort();
+        entries
+    };
+    let before = listing();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o555))?;
+    }
+
+    let mut store = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store
+            .set("key3".to_owned(), "value3".to_owned())
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::ReadOnly)
+    ));
+    assert!(store.remove("key1".to_owned()).is_err());
+    drop(store);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755))?;
+    }
+    assert_eq!(listing(), before);
+
+    Ok(())
+}
+
+// IO failures should exit with their own code and report on stderr.
+#[cfg(feature = "cli")]
+#[test]
+fn cli_io_error() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // The engine marker being a directory makes reading it fail.
+    fs::create_dir(temp_dir.path().join("engine")).unwrap();
+
+    for args in [
+        vec!["get", "key1"],
+        vec!["set", "key1", "value1"],
+        vec!["rm", "key1"],
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(3)
+            .stdout(is_empty())
+            .stderr(is_empty().not());
+    }
+}
+
+// The marker check should reject directories written by another engine.
+#[test]
+fn engine_marker_check() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    kvs::verify_engine_marker(temp_dir.path(), "kvs")?;
+
+    drop(KvStore::open(temp_dir.path())?);
+    kvs::verify_engine_marker(temp_dir.path(), "kvs")?;
+    assert!(matches!(
+        kvs::verify_engine_marker(temp_dir.path(), "sled")
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::WrongEngine { found, expected }) if found == "kvs" && expected == "sled"
+    ));
+
+    fs::write(
+        temp_dir.path().join("engine"),
+        r#"{"engine":"sled","format_version":1}"#,
+    )?;
+    for result in [
+        KvStore::open(temp_dir.path()).map(drop),
+        KvStore::open_with_engine_marker_check(temp_dir.path()).map(drop),
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new()).map(drop),
+    ] {
+        assert!(matches!(
+            result.unwrap_err().downcast::<KvSError>(),
+            Ok(KvSError::WrongEngine { found, expected }) if found == "sled" && expected == "kvs"
+        ));
+    }
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine"))?,
+        r#"{"engine":"sled","format_version":1}"#
+    );
+
+    Ok(())
+}
+
+// Tombstones should survive compaction inside the retention window only.
+#[test]
+fn tombstone_retention() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(
+        Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap(),
+    ));
+    let options = KvStoreOptions::new()
+        .clock(clock.clone())
+        .tombstone_retention(Duration::hours(1));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let has_tombstone = || {
+        log_files(temp_dir.path())
+            .iter()
+            .any(|path| String::from_utf8_lossy(&fs::read(path).unwrap()).contains("removed_key"))
+    };
+    let fill = |store: &mut KvStore| -> CommandResult<()> {
+        let compactions = store.compactions();
+        let value = "v".repeat(1024);
+        while store.compactions() == compactions {
+            store.set("filler".to_owned(), value.clone())?;
+        }
+        Ok(())
+    };
+
+    store.set("removed_key".to_owned(), "value".to_owned())?;
+    store.remove("removed_key".to_owned())?;
+
+    clock.advance(Duration::minutes(30));
+    fill(&mut store)?;
+    assert!(has_tombstone());
+
+    clock.advance(Duration::minutes(31));
+    fill(&mut store)?;
+    assert!(!has_tombstone());
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("removed_key".to_owned())?, None);
+
+    Ok(())
+}
+
+// Sequence numbers shouldn't be handed out again after a compaction drops
+// the records carrying the highest ones and the store is reopened.
+#[test]
+fn changes_since_after_compaction() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Past the compaction threshold, so it seals the active file and its
+    // own, leaving files to merge.
+    store.set("key2".to_owned(), "l".repeat(2 * 1024 * 1024))?;
+    for iter in 0..3 {
+        store.set("key2".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key2".to_owned())?;
+    store.consolidate(u64::MAX)?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let changes: Vec<_> = store.changes_since(6)?.collect();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].0, 7);
+
+    Ok(())
+}
+
+// A follower fed `changes_since` should converge to the leader's state.
+#[test]
+fn changes_since() -> CommandResult<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut leader = KvStore::open(leader_dir.path())?;
+    let mut follower = KvStore::open(follower_dir.path())?;
+
+    let entries = |store: &mut KvStore| -> CommandResult<Vec<Entry>> { store.cursor().collect() };
+    let sync = |leader: &mut KvStore, follower: &mut KvStore, since: u64| -> CommandResult<u64> {
+        let mut last_seq = since;
+        for (seq, change) in leader.changes_since(since)? {
+            assert!(seq > last_seq);
+            follower.apply(change)?;
+            last_seq = seq;
+        }
+        Ok(last_seq)
+    };
+
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.set("key1".to_owned(), "value3".to_owned())?;
+    leader.remove("key2".to_owned())?;
+    let seq = sync(&mut leader, &mut follower, 0)?;
+    assert_eq!(seq, 4);
+    assert_eq!(entries(&mut follower)?, entries(&mut leader)?);
+
+    // Sequence numbers carry on after a reopen.
+    drop(leader);
+    let mut leader = KvStore::open(leader_dir.path())?;
+    assert_eq!(leader.changes_since(seq)?.count(), 0);
+    leader.set_all(vec![
+        ("user:1".to_owned(), "a".to_owned()),
+        ("user:2".to_owned(), "b".to_owned()),
+    ])?;
+    leader.delete_prefix("user:1")?;
+    leader.set("key2".to_owned(), "value4".to_owned())?;
+    let seq = sync(&mut leader, &mut follower, seq)?;
+    assert_eq!(seq, 8);
+    assert_eq!(entries(&mut follower)?, entries(&mut leader)?);
+
+    Ok(())
+}
+
+// Data rewritten by a compaction should survive the process dying right
+// after it, without the store being dropped.
+#[test]
+fn crash_after_compaction() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 100), format!("{}{}", value, iter))?;
+    }
+    assert!(store.compactions() >= 1);
+    std::mem::forget(store);
+
+    // Read-only, as the forgotten store still holds the write lock.
+    let mut store = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    for iter in 1900..2000 {
+        assert_eq!(
+            store.get(format!("key{}", iter % 100))?,
+            Some(format!("{}{}", value, iter))
+        );
+    }
+
+    Ok(())
+}
+
+// Each codec should round-trip records and be usable as a store's codec.
+#[test]
+fn codecs() -> CommandResult<()> {
+    let codecs: Vec<Arc<dyn Codec>> = vec![Arc::new(JsonCodec), Arc::new(BincodeCodec)];
+    for codec in codecs {
+        for command in [
+            CommandLog::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            CommandLog::Remove {
+                key: "key1".to_owned(),
+                removed_at: Some(42),
+            },
+        ] {
+            let bytes = codec.encode(7, &command)?;
+            assert_eq!(codec.decode(&bytes)?, (7, command));
+        }
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().codec(codec.clone());
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// A store should refuse to open with a codec other than the one it was
+// written with.
+#[test]
+fn codec_mismatch() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().codec(Arc::new(BincodeCodec));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for result in [
+        KvStore::open(temp_dir.path()).map(drop),
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new()).map(drop),
+    ] {
+        match result.unwrap_err().downcast::<KvSError>() {
+            Ok(KvSError::CodecMismatch { found, expected }) => {
+                assert_eq!(found, "bincode");
+                assert_eq!(expected, "json");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    Ok(())
+}
+
+// A store should refuse to open with a layout other than the one it was
+// written with, rather than miss its files.
+#[test]
+fn layout_mismatch() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().layout(LogLayout::Nested { bucket_size: 4 });
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for result in [
+        KvStore::open(temp_dir.path()).map(drop),
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new()).map(drop),
+    ] {
+        match result.unwrap_err().downcast::<KvSError>() {
+            Ok(KvSError::LayoutMismatch { found, expected }) => {
+                assert_eq!(found, "Nested { bucket_size: 4 }");
+                assert_eq!(expected, "Flat");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    assert!(log_files(temp_dir.path()).is_empty());
+
+    Ok(())
+}
+
+// Writes should be on disk once the sync interval has passed, and the sync
+// thread should stop with the store.
+#[test]
+fn background_sync() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_interval(std::time::Duration::from_millis(10));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let started = std::time::Instant::now();
+    drop(store);
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+    Ok(())
+}
+
+// A sync interval should be refused along with a flush interval, whose
+// coalesced records the sync thread can't reach.
+#[test]
+fn background_sync_refuses_flush_interval() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .sync_interval(std::time::Duration::from_millis(10))
+        .flush_interval(std::time::Duration::from_secs(3600));
+    assert!(KvStore::open_with_options(temp_dir.path(), options).is_err());
+
+    Ok(())
+}
+
+// With a sync interval far off, only the active file should be left
+// unsynced: rotating to a new file while writing syncs the one it seals.
+#[test]
+fn sealed_files_synced() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_interval(std::time::Duration::from_secs(3600));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.health()?.unsynced_writes);
+    // Values past the compaction threshold seal the active file, then their
+    // own.
+    let large_value = "l".repeat(2 * 1024 * 1024);
+    store.set("large".to_owned(), large_value.clone())?;
+    assert_eq!(log_files(temp_dir.path()).len(), 3);
+    assert!(!store.health()?.unsynced_writes);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.health()?.unsynced_writes);
+    std::mem::forget(store);
+
+    // Read-only, as the forgotten store still holds the write lock.
+    let mut store = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(large_value));
+
+    Ok(())
+}
+
+// A store reopened for writing on a full latest file, as left by a store
+// that died before rotating, should seal it and write to a new generation.
+#[test]
+fn full_latest_file_on_reopen() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().disable_compaction();
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let large_value = "l".repeat(2 * 1024 * 1024);
+    store.set("large".to_owned(), large_value.clone())?;
+    drop(store);
+
+    // Drop the files rotated to after the large value, leaving it latest.
+    let files = log_files(temp_dir.path());
+    let full_index = files
+        .iter()
+        .position(|file| fs::metadata(file).unwrap().len() >= 1024 * 1024)
+        .expect("no full log file");
+    for file in &files[full_index + 1..] {
+        fs::remove_file(file)?;
+    }
+    let full_file = files[full_index].clone();
+    let full_size = fs::metadata(&full_file)?.len();
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let files = log_files(temp_dir.path());
+    assert_eq!(files.len(), full_index + 2);
+    assert_eq!(files[full_index], full_file);
+    assert_eq!(fs::metadata(&full_file)?.len(), full_size);
+    assert!(fs::metadata(&files[full_index + 1])?.len() > 0);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large_value));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A store from `open_in_memory` should delete its directory when dropped.
+#[test]
+fn open_in_memory() -> CommandResult<()> {
+    let mut store = KvStore::open_in_memory()?;
+    let path = store.path().to_owned();
+    assert!(path.is_dir());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!log_files(&path).is_empty());
+
+    drop(store);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+// `close` should make writes durable and release the lock, and report a
+// failure to sync them instead of swallowing it like dropping the store.
+#[test]
+fn close() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().flush_interval(std::time::Duration::from_secs(3600));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    fs::remove_dir_all(temp_dir.path())?;
+    assert!(store.close().is_err());
+
+    Ok(())
+}
+
+// A fresh directory should get seeded, one with keys should be left alone.
+#[test]
+fn open_or_create_with_seed() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let seed = |value: &str| {
+        (1..=3)
+            .map(|iter| (format!("key{}", iter), value.to_owned()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut store = KvStore::open_or_create_with_seed(temp_dir.path(), seed("seeded"))?;
+    for iter in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some("seeded".to_owned())
+        );
+    }
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_or_create_with_seed(temp_dir.path(), seed("reseeded"))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("seeded".to_owned()));
+
+    Ok(())
+}
+
+// New generations should follow the highest existing one, compared as
+// numbers, whatever names earlier files were given.
+#[test]
+fn generation_counter() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_name =
+        |path: &std::path::PathBuf| path.file_name().unwrap().to_str().unwrap().to_owned();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    drop(store);
+    let files = log_files(temp_dir.path());
+    assert_eq!(
+        files.iter().map(file_name).collect::<Vec<_>>(),
+        ["kvlog_1.cmdlog"]
+    );
+    // As if named by a clock that has since gone back.
+    fs::rename(&files[0], temp_dir.path().join("kvlog_9.cmdlog"))?;
+
+    let options = KvStoreOptions::new().initial_generation(10);
+    let mut store = KvStore::open_with_options(other_dir.path(), options)?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    fs::copy(
+        other_dir.path().join("kvlog_10.cmdlog"),
+        temp_dir.path().join("kvlog_10.cmdlog"),
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+
+    let value = "v".repeat(1024);
+    while store.compactions() == 0 {
+        store.set("key2".to_owned(), value.clone())?;
+    }
+    for path in log_files(temp_dir.path()) {
+        let generation: u64 = file_name(&path)
+            .trim_start_matches("kvlog_")
+            .trim_end_matches(".cmdlog")
+            .parse()
+            .unwrap();
+        assert!(generation > 10);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// `remove_many` should remove the present keys and count only those.
+#[test]
+fn remove_many() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let keys: Vec<String> = ["key0", "key2", "missing", "key2", "key4"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    assert_eq!(store.remove_many(&keys)?, 3);
+    assert_eq!(store.remove_many(&keys)?, 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        let expected = (i % 2 == 1).then(|| format!("value{}", i));
+        assert_eq!(store.get(format!("key{}", i))?, expected);
+    }
+
+    Ok(())
+}
+
+// `debug_dump` should list every record, marking superseded ones dead.
+#[test]
+fn debug_dump() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut dump = Vec::new();
+    store.debug_dump(&mut dump)?;
+    let dump = String::from_utf8(dump)?;
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("kvlog_1.cmdlog pos=0 "));
+    assert!(lines[0].contains(" seq=1 dead ") && lines[0].contains("\"value1\""));
+    assert!(lines[1].contains(" seq=2 live ") && lines[1].contains("\"value2\""));
+    assert!(lines[2].contains(" seq=3 dead ") && lines[2].contains("\"value3\""));
+    assert!(lines[3].contains(" seq=4 dead Remove"));
+
+    Ok(())
+}
+
+fn write_through_engine(mut engine: impl KvsEngine, thread_id: usize) -> CommandResult<()> {
+    for i in 0..50 {
+        engine.set(format!("key{}_{}", thread_id, i), format!("value{}", i))?;
+    }
+    engine.remove(format!("key{}_0", thread_id))?;
+    Ok(())
+}
+
+// Shared and borrowed engines should be usable through `KvsEngine` directly.
+#[test]
+fn shared_engine() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(Mutex::new(KvStore::open(temp_dir.path())?));
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || write_through_engine(store, thread_id))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut store = Arc::try_unwrap(store).ok().unwrap().into_inner().unwrap();
+    write_through_engine(&mut store, 4)?;
+    write_through_engine(Box::new(InMemoryKvsEngine::new()), 5)?;
+    for thread_id in 0..5 {
+        assert_eq!(store.get(format!("key{}_0", thread_id))?, None);
+        assert_eq!(
+            store.get(format!("key{}_49", thread_id))?,
+            Some("value49".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Keys over `max_key_bytes` should be rejected without writing to the log.
+#[test]
+fn max_key_bytes() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_key_bytes(8);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_before: Vec<Vec<u8>> = log_files(temp_dir.path())
+        .iter()
+        .map(|path| fs::read(path).unwrap())
+        .collect();
+
+    let long_key = "k".repeat(9);
+    for result in [
+        store.set(long_key.clone(), "value".to_owned()),
+        store.set_all(vec![(long_key.clone(), "value".to_owned())]),
+        store.remove(long_key.clone()),
+    ] {
+        match result.unwrap_err().downcast::<KvSError>() {
+            Ok(KvSError::KeyTooLarge { size, limit }) => {
+                assert_eq!(size, 9);
+                assert_eq!(limit, 8);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    let log_after: Vec<Vec<u8>> = log_files(temp_dir.path())
+        .iter()
+        .map(|path| fs::read(path).unwrap())
+        .collect();
+    assert_eq!(log_after, log_before);
+    assert_eq!(store.get(long_key)?, None);
+
+    Ok(())
+}
+
+// Compaction stats should account for every record a compaction scanned.
+#[test]
+fn compaction_stats() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    let mut writes = 0;
+    while store.compactions() == 0 {
+        store.set(format!("key{}", writes % 10), value.clone())?;
+        writes += 1;
+    }
+
+    let stats = store.stats();
+    // The write that triggered the compaction lands after it.
+    assert_eq!(stats.compaction_records_scanned, writes - 1);
+    assert_eq!(
+        stats.compaction_records_kept + stats.compaction_records_dropped,
+        stats.compaction_records_scanned
+    );
+    assert_eq!(stats.compaction_records_kept, 10);
+    assert!(stats.compaction_bytes_rewritten > 10 * 1024);
+
+    Ok(())
+}
+
+// Writes made before a barrier should survive the process dying, without
+// the store being dropped.
+#[test]
+fn barrier() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store.barrier()?;
+    // Not covered by the barrier, may or may not have reached the disk.
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    std::mem::forget(store);
+
+    // Read-only, as the forgotten store still holds the write lock.
+    let mut store = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let mut reader = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?;
+    reader.barrier()?;
+
+    Ok(())
+}
+
+// Stats after `reset_stats` should only count what happened since.
+#[test]
+fn reset_stats() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_cache_capacity(10);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = "v".repeat(1024);
+    while store.compactions() == 0 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    store.get("key0".to_owned())?;
+    store.get("key0".to_owned())?;
+    assert_ne!(store.stats(), Stats::default());
+
+    store.reset_stats();
+    assert_eq!(store.stats(), Stats::default());
+    assert_eq!(store.compactions(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key0".to_owned())?;
+    assert_eq!(
+        store.stats(),
+        Stats {
+            cache_hits: 2,
+            cache_misses: 1,
+            ..Stats::default()
+        }
+    );
+    assert_eq!(store.compactions(), 0);
+
+    Ok(())
+}
+
+// `consolidate` should merge small log files and keep every live key.
+#[test]
+fn consolidate() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // As if left behind by crashes, each in its own generation.
+    for generation in 1..=5 {
+        write_generation(temp_dir.path(), generation, |store| {
+            store.set(format!("key{}", generation), format!("value{}", generation))?;
+            store.set("shared".to_owned(), format!("value{}", generation))?;
+            if generation == 1 {
+                store.set("big".to_owned(), "v".repeat(2048))?;
+            }
+            if generation == 4 {
+                store.remove("key4".to_owned())?;
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(log_files(temp_dir.path()).len(), 5);
+    store.consolidate(1024)?;
+    // The first file is over the floor and stays as it is.
+    assert_eq!(log_files(temp_dir.path()).len(), 2);
+
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for generation in [1, 2, 3, 5, 6] {
+        assert_eq!(
+            store.get(format!("key{}", generation))?,
+            Some(format!("value{}", generation))
+        );
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("shared".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("big".to_owned())?, Some("v".repeat(2048)));
+
+    Ok(())
+}
+
+// `consolidate` should keep tombstones of keys whose values are in a file
+// it leaves alone, so they stay removed after a reopen.
+#[test]
+fn consolidate_keeps_tombstones_of_unmerged_files() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for generation in 1..=3 {
+        write_generation(temp_dir.path(), generation, |store| {
+            match generation {
+                1 => {
+                    store.set("removed".to_owned(), "value".to_owned())?;
+                    store.set("big".to_owned(), "v".repeat(2048))?;
+                }
+                2 => {
+                    store.set("removed".to_owned(), "value".to_owned())?;
+                    store.remove("removed".to_owned())?;
+                }
+                _ => store.set("key3".to_owned(), "value3".to_owned())?,
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.consolidate(1024)?;
+    assert_eq!(log_files(temp_dir.path()).len(), 2);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `disk_usage` should grow with writes and shrink once compaction drops
+// the overwritten values.
+#[test]
+fn disk_usage() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.disk_usage()?, 0);
+
+    let value = "v".repeat(1024);
+    let mut last_usage = 0;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+        let usage = store.disk_usage()?;
+        assert!(usage > last_usage);
+        last_usage = usage;
+    }
+
+    while store.compactions() == 0 {
+        store.set("key0".to_owned(), value.clone())?;
+        last_usage = last_usage.max(store.disk_usage()?);
+    }
+    assert!(store.disk_usage()? < last_usage);
+
+    Ok(())
+}
+
+// Stores with different log file names should share a directory without
+// recovering each other's files.
+#[test]
+fn log_file_naming() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let custom = || {
+        KvStoreOptions::new()
+            .log_file_prefix("custom")
+            .log_file_extension("log")
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), custom())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "custom".to_owned())?;
+    store.set("key2".to_owned(), "custom".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("custom_1.log").exists());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), custom())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("custom".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("custom".to_owned()));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A failed compaction should leave the store usable, and so should a
+// thread panicking while it holds a shared store's lock.
+#[test]
+fn compaction_failure_recovery() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(Mutex::new(KvStore::open(temp_dir.path())?));
+    let mut engine = Arc::clone(&store);
+
+    // A file where compaction stages its output makes it fail.
+    let staging_path = temp_dir.path().join(".compacting");
+    fs::write(&staging_path, "")?;
+    let value = "v".repeat(1024);
+    let mut writes = 0;
+    let err = loop {
+        match engine.set(format!("key{}", writes % 10), value.clone()) {
+            Ok(()) => writes += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(err.downcast_ref::<std::io::Error>().is_some());
+    assert_eq!(store.lock().unwrap().compactions(), 0);
+    for key_id in 0..10 {
+        assert_eq!(engine.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    let panicking = Arc::clone(&store);
+    let result = thread::spawn(move || {
+        let _guard = panicking.lock().unwrap();
+        panic!("panic while holding the store");
+    })
+    .join();
+    assert!(result.is_err());
+    assert!(store.is_poisoned());
+
+    fs::remove_file(&staging_path)?;
+    engine.set("key0".to_owned(), format!("{}0", value))?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, Some(format!("{}0", value)));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some(value));
+    let store = store.lock().unwrap_or_else(PoisonError::into_inner);
+    assert_eq!(store.compactions(), 1);
+
+    Ok(())
+}
+
+// Removing an absent key should only succeed with idempotent removes, and
+// then without touching the log.
+#[test]
+fn idempotent_remove() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store
+            .remove("key1".to_owned())
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::KeyNotFound { .. })
+    ));
+    drop(store);
+
+    let options = KvStoreOptions::new().idempotent_remove(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let usage = store.disk_usage()?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.disk_usage()?, usage);
+    assert!(store.remove(String::new()).is_err());
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Cached values returned by `get_arc` should share one allocation.
+#[test]
+fn get_arc() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().value_cache_capacity(10);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let first = store.get_arc("key1".to_owned())?.unwrap();
+    let second = store.get_arc("key1".to_owned())?.unwrap();
+    assert_eq!(&*first, "value1");
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_arc("key2".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let third = store.get_arc("key1".to_owned())?.unwrap();
+    assert_eq!(&*third, "value2");
+    assert_eq!(&*first, "value1");
+
+    Ok(())
+}
+
+// A directory should take one writer at a time, while read-only stores
+// open next to it follow its writes through `reload`.
+#[test]
+fn single_writer_many_readers() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path())
+            .map(drop)
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::Locked)
+    ));
+
+    let mut readers = [
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?,
+        KvStore::open_read_only(temp_dir.path(), KvStoreOptions::new())?,
+    ];
+    for reader in readers.iter_mut() {
+        assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    writer.remove("key1".to_owned())?;
+    for reader in readers.iter_mut() {
+        reader.reload()?;
+        assert_eq!(reader.get("key1".to_owned())?, None);
+        assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    drop(writer);
+    let mut writer = KvStore::open(temp_dir.path())?;
+    assert_eq!(writer.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Opening a store with more log files than `max_generations` should
+// compact them into fewer.
+#[test]
+fn max_generations() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // As if left behind by crashes, each in its own generation.
+    for generation in 1..=8 {
+        write_generation(temp_dir.path(), generation, |store| {
+            store.set(format!("key{}", generation), format!("value{}", generation))
+        })?;
+    }
+
+    drop(KvStore::open(temp_dir.path())?);
+    assert_eq!(log_files(temp_dir.path()).len(), 8);
+
+    let options = KvStoreOptions::new().max_generations(3);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(log_files(temp_dir.path()).len() <= 3);
+    assert_eq!(store.compactions(), 1);
+    for generation in 1..=8 {
+        assert_eq!(
+            store.get(format!("key{}", generation))?,
+            Some(format!("value{}", generation))
+        );
+    }
+
+    Ok(())
+}
+
+// Opening with a lock timeout should fail once the timeout passes while
+// another thread holds the store, and succeed once it lets go.
+#[test]
+fn open_with_lock_timeout() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().to_owned();
+    let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let holder = thread::spawn(move || {
+        let store = KvStore::open(path).unwrap();
+        locked_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        drop(store);
+    });
+    locked_rx.recv().unwrap();
+
+    let timeout = std::time::Duration::from_millis(100);
+    assert!(matches!(
+        KvStore::open_with_lock_timeout(temp_dir.path(), timeout)
+            .map(drop)
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::Locked)
+    ));
+
+    release_tx.send(()).unwrap();
+    let mut store =
+        KvStore::open_with_lock_timeout(temp_dir.path(), std::time::Duration::from_secs(10))?;
+    holder.join().unwrap();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Ok(())
+}
+
+// After `compact_key` the key's live record should be the newest one in
+// the active log file.
+#[test]
+fn compact_key() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set("hot".to_owned(), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    store.compact_key("hot")?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value2".to_owned()));
+    assert!(store.compact_key("missing").is_err());
+
+    let mut dump = Vec::new();
+    store.debug_dump(&mut dump)?;
+    let dump = String::from_utf8(dump)?;
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[4].starts_with("kvlog_1.cmdlog "));
+    assert!(lines[4].contains(" live ") && lines[4].contains("\"value2\""));
+    assert!(lines[2].contains(" dead ") && lines[2].contains("\"value2\""));
+    assert!(lines[3].contains(" live ") && lines[3].contains("\"other\""));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Scan results should serialize to JSON and back unchanged.
+#[test]
+fn entry_serde() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "multi\nline \"välue\"".to_owned())?;
+
+    let entries = store.cursor().collect::<CommandResult<Vec<_>>>()?;
+    let json = serde_json::to_string(&entries)?;
+    assert!(json.starts_with(r#"[{"key":"key1","value":"value1"},"#));
+    assert_eq!(serde_json::from_str::<Vec<Entry>>(&json)?, entries);
+
+    Ok(())
+}
+
+// With a dead ratio set, overwriting a key should trigger compaction long
+// before the log reaches the size threshold, while live data shouldn't.
+#[test]
+fn compaction_dead_ratio() -> CommandResult<()> {
+    let value = "v".repeat(1024);
+    let write = |options: KvStoreOptions, overwrite: bool| -> CommandResult<(TempDir, KvStore)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..200 {
+            let key = if overwrite { 0 } else { i };
+            store.set(format!("key{}", key), value.clone())?;
+        }
+        Ok((temp_dir, store))
+    };
+
+    let (_temp_dir, mut store) = write(KvStoreOptions::new().compaction_dead_ratio(0.5), true)?;
+    assert!(store.compactions() >= 1);
+    assert!(store.disk_usage()? < 100 * 1024);
+    assert_eq!(store.get("key0".to_owned())?, Some(value.clone()));
+
+    let (_temp_dir, store) = write(KvStoreOptions::new(), true)?;
+    assert_eq!(store.compactions(), 0);
+    let (_temp_dir, store) = write(KvStoreOptions::new().compaction_dead_ratio(0.5), false)?;
+    assert_eq!(store.compactions(), 0);
+
+    Ok(())
+}
+
+// `health` should agree with the other accessors and the operations made.
+#[test]
+fn health() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let health = store.health()?;
+    assert_eq!(health.live_keys, 0);
+    assert_eq!(health.reclaimable_bytes, 0);
+    assert!(!health.unsynced_writes);
+
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let health = store.health()?;
+    assert_eq!(health.live_keys, 2);
+    assert_eq!(health.log_files, log_files(temp_dir.path()).len());
+    assert_eq!(health.disk_usage, store.disk_usage()?);
+    // Two overwritten or removed values and a tombstone.
+    assert!(health.reclaimable_bytes > 0);
+    assert!(health.reclaimable_bytes < health.disk_usage);
+    assert_eq!(health.last_compaction_at, None);
+    assert!(health.unsynced_writes);
+
+    store.barrier()?;
+    assert!(!store.health()?.unsynced_writes);
+
+    let value = "v".repeat(1024);
+    while store.compactions() == 0 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    let health = store.health()?;
+    assert_eq!(health.live_keys, 2);
+    assert_eq!(health.last_compaction_at, store.last_compaction_at());
+    assert!(health.reclaimable_bytes < health.disk_usage / 2);
+
+    Ok(())
+}
+
+// Small writes should stay coalesced until the interval passes or a read
+// or barrier needs them on disk.
+#[test]
+fn flush_interval() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().flush_interval(std::time::Duration::from_secs(3600));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    assert_eq!(store.disk_usage()?, 0);
+
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    let usage = store.disk_usage()?;
+    assert!(usage > 0);
+
+    store.set("key10".to_owned(), "value".to_owned())?;
+    assert_eq!(store.disk_usage()?, usage);
+    store.barrier()?;
+    assert!(store.disk_usage()? > usage);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..11 {
+        assert_eq!(store.get(format!("key{}", iter))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Records held back by a flush interval should still be seen by
+// `changes_since` and `debug_dump`, so followers of an idle store catch up.
+#[test]
+fn flush_interval_changes_since() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().flush_interval(std::time::Duration::from_secs(60));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.changes_since(0)?.count(), 1);
+    let mut dump = Vec::new();
+    store.debug_dump(&mut dump)?;
+    assert_eq!(String::from_utf8(dump)?.lines().count(), 1);
+
+    Ok(())
+}
+
+// Collects the `file` field of every event, standing in for a real
+// subscriber
+struct FileEvents(Arc<Mutex<Vec<String>>>);
+
+impl tracing::field::Visit for FileEvents {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "file" {
+            self.0.lock().unwrap().push(format!("{:?}", value));
+        }
+    }
+}
+
+struct CapturingSubscriber(Arc<Mutex<Vec<String>>>);
+
+impl tracing::Subscriber for CapturingSubscriber {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        event.record(&mut FileEvents(Arc::clone(&self.0)));
+    }
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+// Recovering a store should report on each log file it replays.
+#[test]
+fn recovery_tracing() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut file_names = Vec::new();
+    for generation in 1..=3 {
+        let file_name = write_generation(temp_dir.path(), generation, |store| {
+            store.set(format!("key{}", generation), format!("value{}", generation))
+        })?;
+        file_names.push(file_name);
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = CapturingSubscriber(Arc::clone(&events));
+    tracing::subscriber::with_default(subscriber, || KvStore::open(temp_dir.path()))?;
+    assert_eq!(*events.lock().unwrap(), file_names);
+
+    Ok(())
+}
+
+// A `ValueRef` should read back the value it was returned for, even after
+// an overwrite, and fail cleanly once a compaction may have moved it.
+#[test]
+fn get_ref() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value_ref: ValueRef = store.set_with_ref("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_ref(&value_ref)?, "value1");
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_ref(&value_ref)?, "value1");
+
+    let value = "v".repeat(1024);
+    while store.compactions() == 0 {
+        store.set("key2".to_owned(), value.clone())?;
+    }
+    assert!(matches!(
+        store
+            .get_ref(&value_ref)
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::StaleValueRef)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should keep returning the values from when it was taken,
+// through overwrites, removes and a compaction deleting its files.
+#[test]
+fn snapshot() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot: Snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+
+    let snapshot_files = log_files(temp_dir.path());
+    let value = "v".repeat(1024);
+    while store.compactions() == 0 {
+        store.set("key4".to_owned(), value.clone())?;
+    }
+    assert!(snapshot_files.iter().any(|path| !path.exists()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// With background compaction the write filling the active file shouldn't
+// wait for the compaction, which a later write should commit. Records are
+// only counted as scanned once a pass commits, so no write should see them
+// change before then. Dropping the store should commit a compaction still
+// running.
+#[test]
+fn background_compaction() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().background_compaction(true);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(1024);
+
+    let mut iter = 0;
+    while log_files(temp_dir.path()).len() < 2 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+        iter += 1;
+    }
+    assert_eq!(store.compactions(), 0);
+    assert_eq!(store.stats().compaction_records_scanned, 0);
+    let full_usage = store.disk_usage()?;
+
+    while store.compactions() == 0 {
+        assert_eq!(store.stats().compaction_records_scanned, 0);
+        thread::sleep(std::time::Duration::from_millis(1));
+        store.set(format!("key{}", iter % 10), value.clone())?;
+        iter += 1;
+    }
+    assert!(store.stats().compaction_records_scanned > 0);
+    assert!(store.disk_usage()? < full_usage);
+    for key in 0..10 {
+        assert_eq!(store.get(format!("key{}", key))?, Some(value.clone()));
+    }
+
+    let files_before = log_files(temp_dir.path()).len();
+    while log_files(temp_dir.path()).len() == files_before {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+        iter += 1;
+    }
+    let files_running = log_files(temp_dir.path()).len();
+    assert_eq!(store.compactions(), 1);
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert!(log_files(temp_dir.path()).len() < files_running);
+    assert!(!temp_dir.path().join(".compacting").exists());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in 0..10 {
+        assert_eq!(store.get(format!("key{}", key))?, Some(value.clone()));
+    }
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Every write to a store should be repeated on its mirror, which can then
+// be opened on its own.
+#[test]
+fn mirror() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mirror_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().mirror(mirror_dir.path());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_all((2..=5).map(|iter| (format!("key{}", iter), format!("value{}", iter))))?;
+    store.set("key2".to_owned(), "value6".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.remove_many(&["key4".to_owned(), "missing".to_owned()])?;
+    let entries = store.filter(|_, _| true)?;
+    drop(store);
+
+    let mut mirror = KvStore::open(mirror_dir.path())?;
+    assert_eq!(mirror.filter(|_, _| true)?, entries);
+    assert_eq!(entries.len(), 3);
+
+    Ok(())
+}
+
+// A mirror that can't be opened should only be warned about, unless its
+// errors are set to fail writes.
+#[test]
+fn mirror_errors() -> CommandResult<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mirror_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _mirror_holder = KvStore::open(mirror_dir.path())?;
+
+    let options = KvStoreOptions::new().mirror(mirror_dir.path());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let options = options.mirror_errors_fail_writes(true);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options)
+            .map(drop)
+            .unwrap_err()
+            .downcast::<KvSError>(),
+        Ok(KvSError::Locked)
+    ));
+
+    Ok(())
+}